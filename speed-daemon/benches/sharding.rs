@@ -0,0 +1,86 @@
+//! Observation throughput of the in-memory storage with a single shard, i.e. one global lock,
+//! against the default number of shards, with many cameras on different roads at once.
+//!
+//! Run with `cargo bench --bench sharding`.
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::{Builder, Runtime};
+
+use speed_daemon::{
+    message::InboundMessageType,
+    policy::TicketPolicy,
+    storage::{MemoryStorage, Storage, DEFAULT_SHARDS},
+    types::{PlateRoadStruct, Road, TimestampCameraStruct},
+};
+
+const ROADS: Road = 64;
+const PLATES_PER_ROAD: u32 = 100;
+const OBSERVATIONS_PER_PLATE: u32 = 4;
+
+fn runtime() -> Runtime {
+    Builder::new_multi_thread()
+        .worker_threads(4)
+        .build()
+        .expect("Unable to build the runtime")
+}
+
+/// What a camera connection and the ticket engine do for every plate: record it, then check it.
+async fn observe_road(storage: Arc<MemoryStorage>, road: Road) {
+    let policy = TicketPolicy::default();
+
+    for observation in 0..OBSERVATIONS_PER_PLATE {
+        for plate in 0..PLATES_PER_ROAD {
+            let plate_road = PlateRoadStruct::new(format!("P{plate}"), road);
+            let ts_camera = TimestampCameraStruct {
+                timestamp: observation * 3600 + plate,
+                camera: InboundMessageType::IAmCamera {
+                    road,
+                    mile: (observation * 70) as u16,
+                    limit: 60,
+                },
+            };
+
+            storage
+                .add_plate_road_timestamp_camera(plate_road.clone(), ts_camera.clone())
+                .await
+                .expect("every observation is unique");
+            storage
+                .get_ticket_for_plate(&plate_road, &ts_camera, &policy)
+                .await;
+        }
+    }
+}
+
+async fn observe_all_roads(shards: usize) {
+    let storage = Arc::new(MemoryStorage::with_shards(shards));
+
+    let cameras: Vec<_> = (0..ROADS)
+        .map(|road| tokio::spawn(observe_road(storage.clone(), road)))
+        .collect();
+
+    for camera in cameras {
+        camera.await.expect("camera task panicked");
+    }
+}
+
+fn sharding(c: &mut Criterion) {
+    let runtime = runtime();
+
+    let mut group = c.benchmark_group("observations");
+    group.throughput(Throughput::Elements(
+        ROADS as u64 * PLATES_PER_ROAD as u64 * OBSERVATIONS_PER_PLATE as u64,
+    ));
+
+    for shards in [1, DEFAULT_SHARDS] {
+        group.bench_with_input(BenchmarkId::new("shards", shards), &shards, |b, &shards| {
+            b.iter(|| runtime.block_on(observe_all_roads(shards)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, sharding);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+//! The client's decoder gets the same treatment as the server's: no panics, no bytes
+//! lost, and the same messages however the input is chunked.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use speed_daemon::codec::ClientCodec;
+use speed_daemon_fuzz::assert_chunking_is_invisible;
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>)| {
+    let (bytes, sizes) = input;
+
+    assert_chunking_is_invisible(ClientCodec::new, &bytes, &sizes);
+});
@@ -0,0 +1,26 @@
+//! Feeds arbitrary bytes to the server's decoder. Whatever it decodes must encode back
+//! to exactly the bytes it came from.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Encoder;
+
+use speed_daemon::codec::{ClientCodec, MessageCodec};
+use speed_daemon_fuzz::decode_all;
+
+fuzz_target!(|input: &[u8]| {
+    let decoded = decode_all(&mut MessageCodec::new(), input);
+
+    let mut encoded = BytesMut::new();
+    for message in decoded.messages {
+        ClientCodec::new()
+            .encode(message, &mut encoded)
+            .expect("a decoded message always fits the protocol");
+    }
+    assert!(
+        input.starts_with(&encoded),
+        "decoded messages don't match the input"
+    );
+});
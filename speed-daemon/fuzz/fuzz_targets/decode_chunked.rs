@@ -0,0 +1,15 @@
+//! Feeds arbitrary bytes to the server's decoder in arbitrary chunks, as a slow or
+//! malicious client would, and compares the result with decoding them in one go.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use speed_daemon::codec::MessageCodec;
+use speed_daemon_fuzz::assert_chunking_is_invisible;
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>)| {
+    let (bytes, sizes) = input;
+
+    assert_chunking_is_invisible(MessageCodec::new, &bytes, &sizes);
+});
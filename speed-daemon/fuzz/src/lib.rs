@@ -0,0 +1,99 @@
+//! Checks shared by the fuzz targets, and by the codec tests, which include this file.
+//!
+//! Run a target with `cargo fuzz run <target>` from the `speed-daemon` directory,
+//! e.g. `cargo fuzz run decode_chunked`.
+
+use std::fmt::Debug;
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+/// Everything a decoder got out of a byte stream.
+#[derive(Debug, PartialEq)]
+pub struct Decoded<T> {
+    pub messages: Vec<T>,
+    /// Whether decoding stopped on an error, after which a connection is dropped.
+    pub failed: bool,
+}
+
+/// Decodes `input` as if it had arrived in a single read.
+pub fn decode_all<D: Decoder>(decoder: &mut D, input: &[u8]) -> Decoded<D::Item> {
+    decode_chunks(decoder, [input])
+}
+
+/// Decodes `chunks` as if they had arrived in separate reads: after each one, decode
+/// messages until the decoder asks for more bytes, like `FramedRead` does.
+///
+/// # Panics
+///
+/// If the decoder consumes bytes without producing a message, or produces one out of nothing.
+pub fn decode_chunks<'a, D: Decoder>(
+    decoder: &mut D,
+    chunks: impl IntoIterator<Item = &'a [u8]>,
+) -> Decoded<D::Item> {
+    let mut buf = BytesMut::new();
+    let mut messages = Vec::new();
+
+    for chunk in chunks {
+        buf.extend_from_slice(chunk);
+
+        loop {
+            let before = buf.len();
+            match decoder.decode(&mut buf) {
+                Ok(Some(message)) => {
+                    assert!(buf.len() < before, "decoded a message from no bytes");
+                    messages.push(message);
+                }
+                Ok(None) => {
+                    assert_eq!(
+                        buf.len(),
+                        before,
+                        "consumed bytes without decoding a message"
+                    );
+                    break;
+                }
+                Err(_) => {
+                    return Decoded {
+                        messages,
+                        failed: true,
+                    }
+                }
+            }
+        }
+    }
+
+    Decoded {
+        messages,
+        failed: false,
+    }
+}
+
+/// Cuts `input` into chunks of the given `sizes`, the last chunk holding whatever is left.
+pub fn split<'a>(input: &'a [u8], sizes: &[u8]) -> Vec<&'a [u8]> {
+    let mut chunks = Vec::with_capacity(sizes.len() + 1);
+    let mut rest = input;
+
+    for &size in sizes {
+        let (chunk, tail) = rest.split_at((size as usize).min(rest.len()));
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+
+    chunks
+}
+
+/// Checks that splitting `input` into chunks doesn't change what the decoder makes of it.
+pub fn assert_chunking_is_invisible<D>(
+    mut new_decoder: impl FnMut() -> D,
+    input: &[u8],
+    sizes: &[u8],
+) where
+    D: Decoder,
+    D::Item: Debug + PartialEq,
+{
+    let whole = decode_all(&mut new_decoder(), input);
+    let chunked = decode_chunks(&mut new_decoder(), split(input, sizes));
+
+    assert_eq!(whole, chunked, "chunk sizes {sizes:?}");
+}
@@ -0,0 +1,148 @@
+//! An optional HTTP listener that lets operators look inside a running daemon.
+//!
+//! `GET /state` answers with a JSON [`StateSnapshot`](crate::snapshot::StateSnapshot), `GET /metrics`
+//! with the server's [`Metrics`](crate::metrics::Metrics) for Prometheus to scrape. There's no
+//! authentication and `/state` copies the whole state, so the listener belongs on a local or
+//! otherwise private address.
+
+use std::{io, time::Duration};
+
+use log::warn;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{errors::SpeedDaemonError, state::Db};
+
+/// Largest request head (request line and headers) that is read.
+const MAX_REQUEST_HEAD: u64 = 8 * 1024;
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers admin requests on `listener` until accepting fails, one connection per request.
+pub async fn run(listener: TcpListener, shared_db: Db) -> Result<(), SpeedDaemonError> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let shared_db = shared_db.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &shared_db).await {
+                warn!("Admin request from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{body}\n").into_bytes(),
+        }
+    }
+
+    fn json<T: serde::Serialize>(value: &T) -> Response {
+        match serde_json::to_vec_pretty(value) {
+            Ok(mut body) => {
+                body.push(b'\n');
+                Response {
+                    status: "200 OK",
+                    content_type: "application/json",
+                    body,
+                }
+            }
+            Err(e) => Response::text("500 Internal Server Error", &e.to_string()),
+        }
+    }
+
+    fn metrics(body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: body.into_bytes(),
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, shared_db: &Db) -> io::Result<()> {
+    let response = match time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream)).await {
+        Ok(Some(request_line)) => respond(&request_line, shared_db).await,
+        Ok(None) => Response::text("400 Bad Request", "Malformed or oversized request"),
+        Err(_) => Response::text("408 Request Timeout", "Request not received in time"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+/// Reads the request head and returns its first line. Headers are skipped, requests have no body.
+/// `None` if the head isn't valid UTF-8, is too large or the client stopped sending halfway.
+async fn read_request_line(stream: &mut TcpStream) -> Option<String> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_HEAD));
+    let mut request_line = String::new();
+    let mut header = String::new();
+
+    if reader.read_line(&mut request_line).await.ok()? == 0 {
+        return None;
+    }
+    loop {
+        header.clear();
+        match reader.read_line(&mut header).await.ok()? {
+            0 => return None,
+            _ if header.trim_end().is_empty() => break,
+            _ => {}
+        }
+    }
+
+    Some(request_line.trim_end().to_string())
+}
+
+async fn respond(request_line: &str, shared_db: &Db) -> Response {
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Response::text("400 Bad Request", "Malformed request line");
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Response::text(
+            "505 HTTP Version Not Supported",
+            "Only HTTP/1.x is supported",
+        );
+    }
+
+    // The query string is ignored.
+    let path = target.split('?').next().unwrap_or_default();
+
+    match (method, path) {
+        ("GET", "/state") => Response::json(&shared_db.snapshot().await),
+        ("GET", "/metrics") => {
+            let pending = shared_db.pending_tickets_by_road().await;
+            Response::metrics(shared_db.metrics().render(&pending))
+        }
+        (_, "/state" | "/metrics") => {
+            Response::text("405 Method Not Allowed", "Only GET is supported")
+        }
+        _ => Response::text(
+            "404 Not Found",
+            &format!("No such endpoint {path:?}, try /state or /metrics"),
+        ),
+    }
+}
@@ -0,0 +1,154 @@
+//! Simulates cameras and dispatchers against a running speed daemon.
+//!
+//! ```text
+//! speed-daemon-client camera --road 123 --mile 8 --limit 60 sightings.txt
+//! speed-daemon-client dispatcher --roads 123,124
+//! ```
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use speed_daemon::{
+    client::Client,
+    message::OutboundMessageType,
+    types::{Limit, Mile, Plate, Road, Timestamp},
+};
+use tokio::time;
+
+#[derive(Debug, Parser)]
+#[command(about = "Simulates cameras and dispatchers against a speed daemon")]
+struct Cli {
+    /// Address of the server.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    server: String,
+
+    /// Ask for a heartbeat every this many deciseconds.
+    #[arg(long)]
+    heartbeat: Option<u32>,
+
+    #[command(subcommand)]
+    role: Role,
+}
+
+#[derive(Debug, Subcommand)]
+enum Role {
+    /// Connect as a camera and replay the plate sightings listed in a file.
+    Camera {
+        #[arg(long)]
+        road: Road,
+
+        #[arg(long)]
+        mile: Mile,
+
+        /// Speed limit in mph.
+        #[arg(long)]
+        limit: Limit,
+
+        /// Pause between two sightings, in milliseconds.
+        #[arg(long, default_value_t = 0)]
+        delay: u64,
+
+        /// One sighting per line: `PLATE TIMESTAMP`. Blank lines and lines starting with `#` are skipped.
+        sightings: PathBuf,
+    },
+
+    /// Connect as a dispatcher and print every ticket received.
+    Dispatcher {
+        /// Comma separated list of roads.
+        #[arg(long, value_delimiter = ',', required = true)]
+        roads: Vec<Road>,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let mut client = match cli.role {
+        Role::Camera {
+            road,
+            mile,
+            limit,
+            delay,
+            sightings,
+        } => {
+            let sightings = read_sightings(&sightings)?;
+
+            let mut client = Client::camera(&cli.server, road, mile, limit).await?;
+            if let Some(interval) = cli.heartbeat {
+                client.want_heartbeat(interval).await?;
+            }
+
+            for (plate, timestamp) in sightings {
+                client.plate(plate, timestamp).await?;
+                if delay > 0 {
+                    time::sleep(Duration::from_millis(delay)).await;
+                }
+            }
+
+            // Done sending: the server closes the connection after its last answer.
+            client.finish().await?;
+            client
+        }
+
+        Role::Dispatcher { roads } => {
+            let mut client = Client::dispatcher(&cli.server, roads).await?;
+            if let Some(interval) = cli.heartbeat {
+                client.want_heartbeat(interval).await?;
+            }
+            client
+        }
+    };
+
+    while let Some(message) = client.recv().await {
+        match message? {
+            OutboundMessageType::Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            } => println!(
+                "ticket {plate} on road {road}: mile {mile1} at {timestamp1} -> mile {mile2} at {timestamp2}, {}.{:02} mph",
+                speed / 100,
+                speed % 100
+            ),
+            OutboundMessageType::Heartbeat => println!("heartbeat"),
+            OutboundMessageType::Error(msg) => bail!("server error: {msg}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn read_sightings(path: &PathBuf) -> anyhow::Result<Vec<(Plate, Timestamp)>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+
+    let mut sightings = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let sighting = line
+            .split_once(char::is_whitespace)
+            .and_then(|(plate, timestamp)| {
+                Some((plate.to_string(), timestamp.trim().parse().ok()?))
+            });
+        match sighting {
+            Some(sighting) => sightings.push(sighting),
+            None => bail!(
+                "{}:{}: expected `PLATE TIMESTAMP`, got {line:?}",
+                path.display(),
+                number + 1
+            ),
+        }
+    }
+
+    Ok(sightings)
+}
@@ -0,0 +1,361 @@
+//! Load generator: drives simulated cameras and dispatchers against a running speed daemon
+//! and reports how it kept up.
+//!
+//! Every road gets a share of the cameras, evenly spaced along it, and a fleet of cars that each
+//! drive past all of them once, at a constant speed, within the first day. A car faster than the
+//! limit must be ticketed exactly once; the report counts the tickets, how long they took to
+//! arrive after the sighting that completed them, and any plate ticketed twice on the same day.
+//!
+//! ```text
+//! speed-daemon-loadgen --roads 10 --cameras 50 --cars 200 --dispatchers 4
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure};
+use clap::Parser;
+use speed_daemon::{
+    client::Client,
+    message::OutboundMessageType,
+    ticket::ticket_days,
+    types::{Day, Limit, Mile, Plate, Road, Timestamp},
+};
+use tokio::{task::JoinSet, time};
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+#[derive(Debug, Parser)]
+#[command(about = "Generates camera and dispatcher load against a speed daemon")]
+struct Args {
+    /// Address of the server.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    server: String,
+
+    /// Number of roads, numbered from 1.
+    #[arg(long, default_value_t = 10)]
+    roads: Road,
+
+    /// Number of cameras, spread over the roads. Every road needs at least two.
+    #[arg(long, default_value_t = 50)]
+    cameras: usize,
+
+    /// Miles between two cameras on the same road.
+    #[arg(long, default_value_t = 10)]
+    spacing: Mile,
+
+    /// Cars driving on every road.
+    #[arg(long, default_value_t = 100)]
+    cars: usize,
+
+    /// Speed limit on every road, in mph.
+    #[arg(long, default_value_t = 60)]
+    limit: Limit,
+
+    /// Slowest car, in mph.
+    #[arg(long, default_value_t = 40)]
+    min_speed: u16,
+
+    /// Fastest car, in mph.
+    #[arg(long, default_value_t = 90)]
+    max_speed: u16,
+
+    /// Number of dispatchers, roads are spread over them.
+    #[arg(long, default_value_t = 2)]
+    dispatchers: usize,
+
+    /// Plates each camera sends per second, 0 for as fast as possible.
+    #[arg(long, default_value_t = 0)]
+    rate: u32,
+
+    /// Seconds to wait for missing tickets once every camera is done.
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// Seed for the car speeds and start times.
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+/// A car driving past every camera on its road.
+#[derive(Clone, Debug)]
+struct Car {
+    plate: Plate,
+    start: Timestamp,
+    speed: u16,
+}
+
+impl Car {
+    /// When the car passes `mile`, rounded to the nearest second.
+    fn timestamp_at(&self, mile: Mile) -> Timestamp {
+        let seconds = (mile as u64 * 3600 * 2 + self.speed as u64) / (2 * self.speed as u64);
+        self.start + seconds as Timestamp
+    }
+}
+
+/// xorshift64*, plenty for spreading speeds and start times.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    /// A number in `low..=high`.
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        low + self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % (high - low + 1)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// When each sighting was sent, by plate and timestamp.
+    sent: HashMap<(Plate, Timestamp), Instant>,
+    plates_sent: usize,
+    tickets: usize,
+    /// From the later of the two sightings of a ticket being sent to the ticket arriving.
+    latencies: Vec<Duration>,
+    /// Days each car was ticketed for.
+    ticketed_days: HashMap<(Plate, Road), HashSet<Day>>,
+    /// Tickets covering a day the car had already been ticketed for.
+    duplicates: usize,
+    last_ticket: Option<Instant>,
+}
+
+impl Stats {
+    fn record_ticket(&mut self, ticket: &OutboundMessageType, received: Instant) {
+        let OutboundMessageType::Ticket {
+            plate,
+            road,
+            timestamp1,
+            timestamp2,
+            ..
+        } = ticket
+        else {
+            return;
+        };
+
+        self.tickets += 1;
+        self.last_ticket = Some(received);
+
+        let completed = [timestamp1, timestamp2]
+            .into_iter()
+            .filter_map(|timestamp| self.sent.get(&(plate.clone(), *timestamp)))
+            .max();
+        if let Some(completed) = completed {
+            self.latencies
+                .push(received.saturating_duration_since(*completed));
+        }
+
+        let days = self
+            .ticketed_days
+            .entry((plate.clone(), *road))
+            .or_default();
+        for day in ticket_days(ticket) {
+            if !days.insert(day) {
+                self.duplicates += 1;
+                eprintln!("{plate} on road {road} was ticketed twice on day {day}");
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    ensure!(args.roads > 0, "at least one road is needed");
+    ensure!(
+        args.cameras >= 2 * args.roads as usize,
+        "every road needs at least two cameras"
+    );
+    ensure!(args.dispatchers > 0, "at least one dispatcher is needed");
+    ensure!(
+        0 < args.min_speed && args.min_speed <= args.max_speed,
+        "speeds must satisfy 0 < min-speed <= max-speed"
+    );
+
+    let roads: Vec<Road> = (1..=args.roads).collect();
+
+    // (road, mile) of every camera, dealt out to the roads in turn
+    let cameras: Vec<(Road, Mile)> = (0..args.cameras)
+        .map(|camera| {
+            let road = roads[camera % roads.len()];
+            let position = (camera / roads.len()) as u64 * args.spacing as u64;
+            (road, position)
+        })
+        .map(|(road, position)| match Mile::try_from(position) {
+            Ok(mile) => Ok((road, mile)),
+            Err(_) => bail!("camera at mile {position} is beyond the last mile marker"),
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    // every car has to pass all the cameras of its road on day 0
+    let last_mile = cameras.iter().map(|(_, mile)| *mile).max().unwrap_or(0) as u64;
+    let longest_trip = (last_mile * 3600).div_ceil(args.min_speed as u64);
+    ensure!(
+        longest_trip < SECONDS_PER_DAY,
+        "the slowest car needs more than a day to pass every camera"
+    );
+
+    let mut rng = Rng::new(args.seed);
+    let mut cars: HashMap<Road, Vec<Car>> = HashMap::new();
+    for &road in &roads {
+        for car in 0..args.cars {
+            cars.entry(road).or_default().push(Car {
+                plate: format!("R{road}C{car}"),
+                start: rng.between(0, SECONDS_PER_DAY - 1 - longest_trip) as Timestamp,
+                speed: rng.between(args.min_speed as u64, args.max_speed as u64) as u16,
+            });
+        }
+    }
+    let expected = cars
+        .values()
+        .flatten()
+        .filter(|car| car.speed > args.limit)
+        .count();
+
+    let stats = Arc::new(Mutex::new(Stats::default()));
+
+    // Dispatchers first, so tickets don't have to wait in the server for one to show up.
+    let mut dispatchers = JoinSet::new();
+    for dispatcher in 0..args.dispatchers {
+        let dispatcher_roads: Vec<Road> = roads
+            .iter()
+            .copied()
+            .filter(|road| *road as usize % args.dispatchers == dispatcher)
+            .collect();
+        if dispatcher_roads.is_empty() {
+            continue;
+        }
+
+        let mut client = Client::dispatcher(&args.server, dispatcher_roads).await?;
+        let stats = stats.clone();
+        dispatchers.spawn(async move {
+            while let Some(message) = client.recv().await {
+                match message? {
+                    ticket @ OutboundMessageType::Ticket { .. } => {
+                        stats.lock().unwrap().record_ticket(&ticket, Instant::now());
+                    }
+                    OutboundMessageType::Error(msg) => bail!("dispatcher got an error: {msg}"),
+                    OutboundMessageType::Heartbeat => {}
+                }
+            }
+            anyhow::Ok(())
+        });
+    }
+
+    let started = Instant::now();
+
+    let mut camera_tasks = JoinSet::new();
+    for (road, mile) in cameras {
+        let mut sightings: Vec<(Plate, Timestamp)> = cars[&road]
+            .iter()
+            .map(|car| (car.plate.clone(), car.timestamp_at(mile)))
+            .collect();
+        // a camera sees the cars in the order they drive by
+        sightings.sort_by_key(|(_, timestamp)| *timestamp);
+
+        let server = args.server.clone();
+        let limit = args.limit;
+        let rate = args.rate;
+        let stats = stats.clone();
+        camera_tasks.spawn(async move {
+            let mut client = Client::camera(&server, road, mile, limit).await?;
+            let mut ticker = (rate > 0).then(|| time::interval(Duration::from_secs(1) / rate));
+
+            for (plate, timestamp) in sightings {
+                if let Some(ticker) = ticker.as_mut() {
+                    ticker.tick().await;
+                }
+
+                // recorded before sending, the ticket may well arrive before the write returns
+                {
+                    let mut stats = stats.lock().unwrap();
+                    stats
+                        .sent
+                        .insert((plate.clone(), timestamp), Instant::now());
+                    stats.plates_sent += 1;
+                }
+
+                client.plate(plate, timestamp).await?;
+            }
+
+            client.finish().await?;
+            anyhow::Ok(())
+        });
+    }
+
+    while let Some(camera) = camera_tasks.join_next().await {
+        camera??;
+    }
+    let sending = started.elapsed();
+
+    // Wait for the tickets still on their way, up to the timeout.
+    let deadline = Instant::now() + Duration::from_secs(args.timeout);
+    while stats.lock().unwrap().tickets < expected && Instant::now() < deadline {
+        if let Some(Ok(Err(e))) = dispatchers.try_join_next() {
+            return Err(e);
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    dispatchers.abort_all();
+
+    let stats = stats.lock().unwrap();
+    let ticketing = stats
+        .last_ticket
+        .map_or(sending, |last| last.duration_since(started));
+
+    let mut latencies = stats.latencies.clone();
+    latencies.sort();
+    let percentile = |p: usize| {
+        latencies
+            .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+
+    println!(
+        "{} cameras on {} roads sent {} plates in {:.2?} ({:.0} plates/s)",
+        args.cameras,
+        args.roads,
+        stats.plates_sent,
+        sending,
+        stats.plates_sent as f64 / sending.as_secs_f64()
+    );
+    println!(
+        "{} of {} expected tickets received in {:.2?} ({:.0} tickets/s)",
+        stats.tickets,
+        expected,
+        ticketing,
+        stats.tickets as f64 / ticketing.as_secs_f64()
+    );
+    println!(
+        "latency from final sighting to ticket: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(50),
+        percentile(99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    println!(
+        "plates ticketed twice on the same day: {}",
+        stats.duplicates
+    );
+
+    ensure!(
+        stats.duplicates == 0,
+        "some plates were ticketed twice a day"
+    );
+    ensure!(
+        stats.tickets == expected,
+        "expected {expected} tickets, got {}",
+        stats.tickets
+    );
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use futures::{SinkExt, StreamExt};
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream, ToSocketAddrs,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{
+    codec::ClientCodec,
+    errors::SpeedDaemonError,
+    message::{InboundMessageType, OutboundMessageType},
+    types::{Limit, Mile, Plate, Road, Timestamp},
+};
+
+/// A connection to a speed daemon, seen from the client side.
+///
+/// It speaks the same protocol as real cameras and dispatchers, so it can be used
+/// to drive end-to-end scenarios against a running server.
+#[derive(Debug)]
+pub struct Client {
+    reader: FramedRead<OwnedReadHalf, ClientCodec>,
+    writer: FramedWrite<OwnedWriteHalf, ClientCodec>,
+}
+
+impl Client {
+    /// Connects to the server without identifying as anything yet.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client, SpeedDaemonError> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+
+        Ok(Client {
+            reader: FramedRead::new(reader, ClientCodec::new()),
+            writer: FramedWrite::new(writer, ClientCodec::new()),
+        })
+    }
+
+    /// Connects and identifies as a camera at `mile` on `road`.
+    pub async fn camera<A: ToSocketAddrs>(
+        addr: A,
+        road: Road,
+        mile: Mile,
+        limit: Limit,
+    ) -> Result<Client, SpeedDaemonError> {
+        let mut client = Client::connect(addr).await?;
+        client
+            .send(InboundMessageType::IAmCamera { road, mile, limit })
+            .await?;
+
+        Ok(client)
+    }
+
+    /// Connects and identifies as a dispatcher for `roads`.
+    pub async fn dispatcher<A: ToSocketAddrs>(
+        addr: A,
+        roads: Vec<Road>,
+    ) -> Result<Client, SpeedDaemonError> {
+        let mut client = Client::connect(addr).await?;
+        client
+            .send(InboundMessageType::IAmDispatcher { roads })
+            .await?;
+
+        Ok(client)
+    }
+
+    pub async fn send(&mut self, message: InboundMessageType) -> Result<(), SpeedDaemonError> {
+        self.writer.send(message).await
+    }
+
+    /// Reports a plate, only valid once identified as a camera.
+    pub async fn plate(
+        &mut self,
+        plate: Plate,
+        timestamp: Timestamp,
+    ) -> Result<(), SpeedDaemonError> {
+        self.send(InboundMessageType::Plate { plate, timestamp })
+            .await
+    }
+
+    /// Asks for a heartbeat every `interval` deciseconds.
+    pub async fn want_heartbeat(&mut self, interval: u32) -> Result<(), SpeedDaemonError> {
+        self.send(InboundMessageType::WantHeartbeat { interval })
+            .await
+    }
+
+    /// Waits for the next message from the server, `None` once it closed the connection.
+    pub async fn recv(&mut self) -> Option<Result<OutboundMessageType, SpeedDaemonError>> {
+        self.reader.next().await
+    }
+
+    /// Tells the server nothing more will be sent. Its answers can still be received.
+    pub async fn finish(&mut self) -> Result<(), SpeedDaemonError> {
+        self.writer.close().await
+    }
+}
@@ -1,16 +1,18 @@
-use log::info;
 use tokio_util::codec::{Decoder, Encoder};
 
 use bytes::{Buf, BufMut, BytesMut};
-use nom::{Err, Needed};
+use nom::Err;
 // use std::{cmp, fmt, io, str, usize};
 
 use crate::{
     errors::SpeedDaemonError,
     message::{InboundMessageType, OutboundMessageType},
-    parsers::parse_message,
+    parsers::{parse_message, parse_outbound_message},
 };
 
+/// The longest string the protocol can carry, its length has to fit in a single byte.
+const MAX_STR_LEN: usize = u8::MAX as usize;
+
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct MessageCodec {}
 
@@ -34,9 +36,16 @@ impl Decoder for MessageCodec {
     type Item = InboundMessageType;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            return Ok(None);
+        // Only parse whole frames, whatever the parsers would make of a partial one.
+        match inbound_frame_len(src)? {
+            Some(frame_len) if src.len() >= frame_len => {}
+            Some(frame_len) => {
+                src.reserve(frame_len - src.len());
+                return Ok(None);
+            }
+            None => return Ok(None),
         }
+
         match parse_message(src) {
             Ok((remaining_bytes, parsed_message)) => {
                 // advance the cursor by the difference between what we read
@@ -46,7 +55,7 @@ impl Decoder for MessageCodec {
                 // return the parsed message
                 Ok(Some(parsed_message))
             }
-            Err(Err::Incomplete(Needed::Size(_))) => Ok(None),
+            Err(Err::Incomplete(_)) => Ok(None),
             Err(_) => Err(SpeedDaemonError::ParseFailure),
         }
     }
@@ -65,13 +74,12 @@ impl Encoder<OutboundMessageType> for MessageCodec {
             OutboundMessageType::Error(err_msg) => {
                 // 1 byte for the message ID, 1 byte for the string length.
                 // Remaining for the length of error message
+                let err_msg = truncate(&err_msg, MAX_STR_LEN);
                 let buffer_size = 1 + 1 + err_msg.len();
                 dst.reserve(buffer_size);
 
                 dst.put_u8(0x10); // msg type
-                dst.put_u8(err_msg.len() as u8); // string length
-                dst.put(err_msg.as_bytes()); // error message
-                // info!("Encoded error message: {:#?}", dst);
+                put_str(dst, err_msg, "error message")?;
             }
 
             OutboundMessageType::Ticket {
@@ -84,12 +92,10 @@ impl Encoder<OutboundMessageType> for MessageCodec {
                 speed,
             } => {
                 let buffer_size = 1 + 1 + plate.len() + 2 + 2 + 4 + 2 + 4 + 2;
-                let plate = plate.as_bytes();
                 dst.reserve(buffer_size);
                 dst.put_u8(0x21);
 
-                dst.put_u8(plate.len() as u8);
-                dst.extend_from_slice(plate);
+                put_str(dst, &plate, "plate")?;
                 dst.put_u16(road);
                 dst.put_u16(mile1);
                 dst.put_u32(timestamp1);
@@ -102,3 +108,153 @@ impl Encoder<OutboundMessageType> for MessageCodec {
         Ok(())
     }
 }
+
+/// Length of the client message at the start of `src`, from its header alone.
+///
+/// `Ok(None)` means not even the header has arrived yet. An unknown message type is an
+/// error straight away, there's no point waiting for more bytes.
+fn inbound_frame_len(src: &[u8]) -> Result<Option<usize>, SpeedDaemonError> {
+    let Some(&message_type) = src.first() else {
+        return Ok(None);
+    };
+
+    let frame_len = match message_type {
+        // Plate: str plate, u32 timestamp
+        0x20 => src.get(1).map(|&plate_len| 1 + 1 + plate_len as usize + 4),
+        // WantHeartbeat: u32 interval
+        0x40 => Some(1 + 4),
+        // IAmCamera: u16 road, u16 mile, u16 limit
+        0x80 => Some(1 + 2 + 2 + 2),
+        // IAmDispatcher: u8 numroads, u16 roads[numroads]
+        0x81 => src.get(1).map(|&numroads| 1 + 1 + 2 * numroads as usize),
+        _ => return Err(SpeedDaemonError::ParseFailure),
+    };
+
+    Ok(frame_len)
+}
+
+/// Length of the server message at the start of `src`, see [`inbound_frame_len`].
+fn outbound_frame_len(src: &[u8]) -> Result<Option<usize>, SpeedDaemonError> {
+    let Some(&message_type) = src.first() else {
+        return Ok(None);
+    };
+
+    let frame_len = match message_type {
+        // Error: str msg
+        0x10 => src.get(1).map(|&msg_len| 1 + 1 + msg_len as usize),
+        // Ticket: str plate, u16 road, u16 mile1, u32 timestamp1, u16 mile2, u32 timestamp2, u16 speed
+        0x21 => src
+            .get(1)
+            .map(|&plate_len| 1 + 1 + plate_len as usize + 2 + 2 + 4 + 2 + 4 + 2),
+        // Heartbeat
+        0x41 => Some(1),
+        _ => return Err(SpeedDaemonError::ParseFailure),
+    };
+
+    Ok(frame_len)
+}
+
+/// Writes a protocol string: a length byte followed by the bytes of `string`.
+fn put_str(dst: &mut BytesMut, string: &str, what: &'static str) -> Result<(), SpeedDaemonError> {
+    let length = u8::try_from(string.len()).map_err(|_| SpeedDaemonError::MessageTooLong(what))?;
+
+    dst.put_u8(length);
+    dst.extend_from_slice(string.as_bytes());
+
+    Ok(())
+}
+
+/// Cuts `string` down to at most `max_len` bytes without splitting a character.
+fn truncate(string: &str, max_len: usize) -> &str {
+    if string.len() <= max_len {
+        return string;
+    }
+
+    let end = (0..=max_len)
+        .rev()
+        .find(|&end| string.is_char_boundary(end))
+        .unwrap_or(0);
+
+    &string[..end]
+}
+
+/// The client side of the protocol: encodes what cameras and dispatchers send
+/// and decodes what the server answers. Handy for test clients and proxies.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ClientCodec {}
+
+impl ClientCodec {
+    /// Creates a new [`ClientCodec`].
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Error = SpeedDaemonError;
+
+    type Item = OutboundMessageType;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match outbound_frame_len(src)? {
+            Some(frame_len) if src.len() >= frame_len => {}
+            Some(frame_len) => {
+                src.reserve(frame_len - src.len());
+                return Ok(None);
+            }
+            None => return Ok(None),
+        }
+
+        match parse_outbound_message(src) {
+            Ok((remaining_bytes, parsed_message)) => {
+                src.advance(src.len() - remaining_bytes.len());
+                Ok(Some(parsed_message))
+            }
+            Err(Err::Incomplete(_)) => Ok(None),
+            Err(_) => Err(SpeedDaemonError::ParseFailure),
+        }
+    }
+}
+
+impl Encoder<InboundMessageType> for ClientCodec {
+    type Error = SpeedDaemonError;
+
+    fn encode(&mut self, item: InboundMessageType, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            InboundMessageType::Plate { plate, timestamp } => {
+                dst.reserve(1 + 1 + plate.len() + 4);
+                dst.put_u8(0x20);
+                put_str(dst, &plate, "plate")?;
+                dst.put_u32(timestamp);
+            }
+
+            InboundMessageType::WantHeartbeat { interval } => {
+                dst.reserve(1 + 4);
+                dst.put_u8(0x40);
+                dst.put_u32(interval);
+            }
+
+            InboundMessageType::IAmCamera { road, mile, limit } => {
+                dst.reserve(1 + 2 + 2 + 2);
+                dst.put_u8(0x80);
+                dst.put_u16(road);
+                dst.put_u16(mile);
+                dst.put_u16(limit);
+            }
+
+            InboundMessageType::IAmDispatcher { roads } => {
+                let numroads = u8::try_from(roads.len())
+                    .map_err(|_| SpeedDaemonError::MessageTooLong("roads"))?;
+
+                dst.reserve(1 + 1 + 2 * roads.len());
+                dst.put_u8(0x81);
+                dst.put_u8(numroads);
+                for road in roads {
+                    dst.put_u16(road);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+//! Startup configuration of the daemon.
+//!
+//! Every setting comes from, in order of precedence: a command-line flag, the environment
+//! variable named in `--help`, the TOML file given with `--config`, and finally the default.
+//! See `speed-daemon.example.toml` for the file format.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::{Parser, ValueEnum};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    errors::SpeedDaemonError,
+    policy::{RetentionPolicy, TicketPolicies, TicketPolicy},
+    queue::{QueueConfig, SlowDispatcherPolicy},
+    storage::DEFAULT_SHARDS,
+    types::Road,
+};
+
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+
+/// Seconds.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// Where observations and tickets are kept.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Everything is lost when the daemon stops.
+    Memory,
+    /// Observations and undelivered tickets survive a restart.
+    Sqlite,
+}
+
+/// Command-line flags. Anything left out falls back to the environment, then the config file.
+#[derive(Debug, Default, Parser)]
+#[command(name = "speed-daemon", about = "Speed limit enforcement server")]
+pub struct Args {
+    /// TOML file to read the configuration from.
+    #[arg(long, env = "SPEED_DAEMON_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Addresses to accept clients on, port 0 lets the OS choose one. [default: 0.0.0.0:8080]
+    #[arg(long, env = "LISTEN_ADDR", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+
+    /// Threads running the server. [default: one per CPU]
+    #[arg(long, env = "WORKER_THREADS")]
+    pub worker_threads: Option<usize>,
+
+    /// Address of the HTTP admin endpoint, e.g. `127.0.0.1:9090`. Keep it private. [default: off]
+    #[arg(long, env = "ADMIN_LISTEN")]
+    pub admin_listen: Option<SocketAddr>,
+
+    /// Seconds to deliver the last tickets on SIGTERM or Ctrl-C before exiting anyway. [default: 10]
+    #[arg(long, env = "SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Log filter, e.g. `debug` or `info,speed_daemon::engine=trace`. [default: info]
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Whether log lines are colored: auto, always or never. [default: always]
+    #[arg(long, env = "LOG_STYLE")]
+    pub log_style: Option<String>,
+
+    /// Storage backend. [default: sqlite if a storage path is set, memory otherwise]
+    #[arg(long, env = "STORAGE_BACKEND")]
+    pub storage: Option<StorageKind>,
+
+    /// SQLite database file.
+    #[arg(long, env = "STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+
+    /// Road state shards, each with its own lock and ticket engine worker. [default: 16]
+    #[arg(long, env = "STATE_SHARDS")]
+    pub shards: Option<usize>,
+
+    /// Messages waiting to be written to a single client. [default: 1024]
+    #[arg(long, env = "CLIENT_QUEUE_CAPACITY")]
+    pub client_queue_capacity: Option<usize>,
+
+    /// Observations waiting for the ticket engine. [default: 4096]
+    #[arg(long, env = "OBSERVATION_QUEUE_CAPACITY")]
+    pub observation_queue_capacity: Option<usize>,
+
+    /// What to do with a dispatcher whose queue is full: drop or block. [default: drop]
+    #[arg(long, env = "SLOW_DISPATCHER")]
+    pub slow_dispatcher: Option<SlowDispatcherPolicy>,
+
+    /// Undelivered tickets kept in memory. [default: 100000]
+    #[arg(long, env = "MAX_PENDING_TICKETS")]
+    pub max_pending_tickets: Option<usize>,
+
+    /// Seconds between two queue depth reports, 0 to never report. [default: 10]
+    #[arg(long, env = "QUEUE_REPORT_INTERVAL")]
+    pub queue_report_interval: Option<u64>,
+
+    /// Which observations to forget, e.g. `max_age=86400,interval=30`. [default: keep everything]
+    #[arg(long, env = "RETENTION_POLICY")]
+    pub retention: Option<RetentionPolicy>,
+
+    /// Ticket policy of every road, e.g. `tolerance=0.5,rounding=down,exact=true`.
+    #[arg(long, env = "TICKET_POLICY")]
+    pub ticket_policy: Option<TicketPolicy>,
+
+    /// Ticket policy of a single road, `ROAD:POLICY`. Can be repeated. `TICKET_POLICY_<road>`
+    /// environment variables work too.
+    #[arg(long, value_parser = parse_road_policy)]
+    pub road_policy: Vec<(Road, TicketPolicy)>,
+}
+
+/// Parses `ROAD:POLICY`, e.g. `123:tolerance=2`.
+fn parse_road_policy(s: &str) -> Result<(Road, TicketPolicy), SpeedDaemonError> {
+    let (road, policy) = s.split_once(':').ok_or_else(|| {
+        SpeedDaemonError::InvalidConfig(format!("expected ROAD:POLICY, got {s:?}"))
+    })?;
+    let road = road
+        .trim()
+        .parse()
+        .map_err(|_| SpeedDaemonError::InvalidConfig(format!("{road:?} is not a road")))?;
+
+    Ok((road, policy.parse()?))
+}
+
+/// The config file. Every key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    listen: Option<Vec<SocketAddr>>,
+    worker_threads: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    admin: AdminSection,
+    log: LogSection,
+    storage: StorageSection,
+    queues: QueueSection,
+    #[serde(deserialize_with = "parsed")]
+    retention: Option<RetentionPolicy>,
+    ticket_policy: TicketPolicySection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AdminSection {
+    listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    level: Option<String>,
+    style: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageSection {
+    backend: Option<StorageKind>,
+    path: Option<PathBuf>,
+    shards: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QueueSection {
+    client_capacity: Option<usize>,
+    observation_capacity: Option<usize>,
+    #[serde(deserialize_with = "parsed")]
+    slow_dispatcher: Option<SlowDispatcherPolicy>,
+    max_pending_tickets: Option<usize>,
+    report_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TicketPolicySection {
+    #[serde(deserialize_with = "parsed")]
+    default: Option<TicketPolicy>,
+    /// Keyed by road number. TOML keys are always strings, they're parsed once the file is read.
+    roads: HashMap<String, String>,
+}
+
+/// Deserializes a string setting with the same parser the command line uses.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<ConfigFile, SpeedDaemonError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            SpeedDaemonError::InvalidConfig(format!("unable to read {}: {e}", path.display()))
+        })?;
+
+        toml::from_str(&contents)
+            .map_err(|e| SpeedDaemonError::InvalidConfig(format!("{}: {e}", path.display())))
+    }
+}
+
+/// Where the daemon keeps its state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StorageConfig {
+    Memory,
+    Sqlite(PathBuf),
+}
+
+/// The complete, validated configuration the daemon runs with.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// `None` lets the runtime start one thread per CPU.
+    pub worker_threads: Option<usize>,
+    /// Where the HTTP admin endpoint listens, `None` to not serve it.
+    pub admin_listen: Option<SocketAddr>,
+    /// How long a graceful shutdown may take.
+    pub shutdown_timeout: Duration,
+    pub log_level: String,
+    pub log_style: String,
+    pub storage: StorageConfig,
+    pub shards: usize,
+    pub queues: QueueConfig,
+    pub retention: RetentionPolicy,
+    pub ticket_policies: TicketPolicies,
+}
+
+impl Config {
+    /// Parses the command line, reads the config file it points at and validates the result.
+    /// Invalid flags end the process with a usage message, like any other clap error.
+    pub fn load() -> Result<Config, SpeedDaemonError> {
+        Config::from_args(Args::parse())
+    }
+
+    /// Layers `args` over the config file they name, if any, and the defaults.
+    pub fn from_args(args: Args) -> Result<Config, SpeedDaemonError> {
+        let file = match &args.config {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let listen = match (args.listen, file.listen) {
+            (cli, _) if !cli.is_empty() => cli,
+            (_, Some(file)) => file,
+            _ => vec![DEFAULT_LISTEN_ADDR.parse().expect("valid default address")],
+        };
+
+        let storage_path = args.storage_path.or(file.storage.path);
+        let storage = match args.storage.or(file.storage.backend) {
+            Some(StorageKind::Sqlite) => match storage_path {
+                Some(path) => StorageConfig::Sqlite(path),
+                None => {
+                    return Err(SpeedDaemonError::InvalidConfig(String::from(
+                        "the sqlite backend needs a storage path",
+                    )))
+                }
+            },
+            Some(StorageKind::Memory) if storage_path.is_some() => {
+                return Err(SpeedDaemonError::InvalidConfig(String::from(
+                    "a storage path is set but the backend is memory",
+                )))
+            }
+            Some(StorageKind::Memory) => StorageConfig::Memory,
+            None => storage_path.map_or(StorageConfig::Memory, StorageConfig::Sqlite),
+        };
+
+        let defaults = QueueConfig::default();
+        let report_interval = args
+            .queue_report_interval
+            .or(file.queues.report_interval)
+            .map_or(defaults.report_interval, |seconds| {
+                (seconds > 0).then(|| Duration::from_secs(seconds))
+            });
+        let queues = QueueConfig {
+            client_capacity: args
+                .client_queue_capacity
+                .or(file.queues.client_capacity)
+                .unwrap_or(defaults.client_capacity),
+            observation_capacity: args
+                .observation_queue_capacity
+                .or(file.queues.observation_capacity)
+                .unwrap_or(defaults.observation_capacity),
+            slow_dispatcher: args
+                .slow_dispatcher
+                .or(file.queues.slow_dispatcher)
+                .unwrap_or(defaults.slow_dispatcher),
+            max_pending_tickets: args
+                .max_pending_tickets
+                .or(file.queues.max_pending_tickets)
+                .unwrap_or(defaults.max_pending_tickets),
+            report_interval,
+        };
+
+        // Per-road policies merge instead of replacing each other, the most specific source wins.
+        let mut ticket_policies = TicketPolicies {
+            default: args
+                .ticket_policy
+                .or(file.ticket_policy.default)
+                .unwrap_or_default(),
+            roads: HashMap::new(),
+        };
+        for (road, policy) in file.ticket_policy.roads {
+            let road = road.trim().parse().map_err(|_| {
+                SpeedDaemonError::InvalidConfig(format!(
+                    "ticket_policy.roads: {road:?} is not a road"
+                ))
+            })?;
+            ticket_policies.roads.insert(road, policy.parse()?);
+        }
+        ticket_policies
+            .roads
+            .extend(TicketPolicies::roads_from_env()?);
+        ticket_policies.roads.extend(args.road_policy);
+
+        let config = Config {
+            listen,
+            worker_threads: args.worker_threads.or(file.worker_threads),
+            admin_listen: args.admin_listen.or(file.admin.listen),
+            shutdown_timeout: Duration::from_secs(
+                args.shutdown_timeout
+                    .or(file.shutdown_timeout)
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            ),
+            log_level: args
+                .log_level
+                .or(file.log.level)
+                .unwrap_or_else(|| String::from("info")),
+            log_style: args
+                .log_style
+                .or(file.log.style)
+                .unwrap_or_else(|| String::from("always")),
+            storage,
+            shards: args
+                .shards
+                .or(file.storage.shards)
+                .unwrap_or(DEFAULT_SHARDS),
+            queues,
+            retention: args.retention.or(file.retention).unwrap_or_default(),
+            ticket_policies,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings the daemon can't start with.
+    pub fn validate(&self) -> Result<(), SpeedDaemonError> {
+        let invalid = |message: &str| Err(SpeedDaemonError::InvalidConfig(String::from(message)));
+
+        if self.listen.is_empty() {
+            return invalid("at least one listen address is needed");
+        }
+        if self.worker_threads == Some(0) {
+            return invalid("worker_threads must be at least 1");
+        }
+        if self.shards == 0 {
+            return invalid("shards must be at least 1");
+        }
+        if !matches!(self.log_style.as_str(), "auto" | "always" | "never") {
+            return Err(SpeedDaemonError::InvalidConfig(format!(
+                "unknown log style {:?}, expected auto, always or never",
+                self.log_style
+            )));
+        }
+
+        self.queues.validate()
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use log::info;
+use tokio::sync::mpsc;
+use tokio_util::task::{task_tracker::TaskTrackerToken, TaskTracker};
+
+use crate::{
+    errors::SpeedDaemonError,
+    state::Db,
+    storage::shard_index,
+    types::{PlateRoadStruct, TimestampCameraStruct},
+};
+
+/// A recorded plate sighting on its way from a camera connection to the ticket engine.
+pub type Observation = (PlateRoadStruct, TimestampCameraStruct);
+
+/// Handle to the long-lived tasks that turn observations from every camera into tickets
+/// and route them to dispatchers. Cloning it is cheap, every camera connection holds one.
+///
+/// Since ticketing lives here rather than in the connection tasks, a ticket is computed and
+/// delivered no matter which connections come and go in the meantime.
+///
+/// The engine runs one worker per shard of road state. Every road is always handled by the
+/// same worker, so the observations of a car are processed in the order they arrived while
+/// different roads are processed in parallel.
+#[derive(Debug, Clone)]
+pub struct TicketEngine {
+    workers: Arc<[mpsc::Sender<(Observation, TaskTrackerToken)>]>,
+    /// Holds a token for every observation until its tickets have been routed.
+    in_flight: TaskTracker,
+}
+
+impl TicketEngine {
+    /// Spawns `workers` workers (at least one) on top of `shared_db`. At most `capacity`
+    /// observations are buffered in total, once a worker's queue is full cameras on its roads
+    /// wait for it to catch up.
+    pub fn spawn(shared_db: Db, capacity: usize, workers: usize) -> TicketEngine {
+        let workers = workers.max(1);
+        let worker_capacity = (capacity / workers).max(1);
+
+        let in_flight = TaskTracker::new();
+
+        let workers = (0..workers)
+            .map(|worker| {
+                let (tx, rx) = mpsc::channel(worker_capacity);
+                tokio::spawn(run_worker(worker, shared_db.clone(), rx));
+                tx
+            })
+            .collect();
+
+        TicketEngine { workers, in_flight }
+    }
+
+    /// Waits until every observation submitted so far has been processed and its tickets
+    /// handed to a dispatcher or parked. Meant for shutdown, once cameras stopped submitting.
+    pub async fn drain(&self) {
+        self.in_flight.close();
+        self.in_flight.wait().await;
+    }
+
+    /// Number of observations waiting to be processed.
+    pub fn queue_depth(&self) -> usize {
+        self.workers
+            .iter()
+            .map(|tx| tx.max_capacity() - tx.capacity())
+            .sum()
+    }
+
+    /// Hands a new observation, already recorded in the shared db, to the worker responsible
+    /// for its road, which checks it for tickets.
+    pub async fn submit(
+        &self,
+        plate_road: PlateRoadStruct,
+        ts_camera: TimestampCameraStruct,
+    ) -> Result<(), SpeedDaemonError> {
+        let worker = &self.workers[shard_index(plate_road.road, self.workers.len())];
+
+        worker
+            .send(((plate_road, ts_camera), self.in_flight.token()))
+            .await
+            .map_err(|_| SpeedDaemonError::EngineStopped)
+    }
+}
+
+async fn run_worker(
+    worker: usize,
+    shared_db: Db,
+    mut rx: mpsc::Receiver<(Observation, TaskTrackerToken)>,
+) {
+    // Observations are handled one at a time, in the order they arrived.
+    // The token is dropped once the observation is done with, and its tickets are on their way.
+    while let Some(((plate_road, ts_camera), _token)) = rx.recv().await {
+        // handle_plate has recorded the observation already and turned away duplicates
+        let policy = shared_db.ticket_policy(plate_road.road);
+        let Some(tickets) = shared_db
+            .get_ticket_for_plate(&plate_road, &ts_camera, &policy)
+            .await
+        else {
+            continue;
+        };
+
+        // Tickets for roads without a dispatcher are parked in the shared db,
+        // handle_i_am_dispatcher delivers them once one shows up. Only a blocked
+        // dispatcher makes this wait, which is then what the cameras wait on too.
+        for ticket in tickets {
+            shared_db.issue_ticket(ticket).await;
+        }
+    }
+
+    info!("Ticket engine worker {} stopped.", worker);
+}
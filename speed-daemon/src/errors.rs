@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::types::{Plate, Timestamp};
+
 /// SpeedDaemonError enumerates all possible errors returned by this library.
 #[derive(Error, Debug)]
 pub enum SpeedDaemonError {
@@ -11,6 +13,18 @@ pub enum SpeedDaemonError {
     #[error("Message type does not match client type")]
     WrongMessageClient,
 
+    /// A plate that isn't made of uppercase letters and digits only
+    #[error("Invalid plate {0:?}")]
+    InvalidPlate(String),
+
+    /// A camera announced a speed limit of 0
+    #[error("Invalid speed limit, must be at least 1 mph")]
+    InvalidLimit,
+
+    /// The same camera already reported the same plate at the same time
+    #[error("Duplicate observation of {plate} at {timestamp}")]
+    DuplicateObservation { plate: Plate, timestamp: Timestamp },
+
     /// Duplicate client
     #[error("Duplicate camera detected")]
     DuplicateCamera,
@@ -19,9 +33,37 @@ pub enum SpeedDaemonError {
     #[error("Duplicate dispatcher detected")]
     DuplicateDispatcher,
 
+    /// A client sent IAmCamera or IAmDispatcher after it already said what it is
+    #[error("Client already identified itself")]
+    AlreadyIdentified,
+
+    /// A client may only ask for heartbeats once per connection
+    #[error("Duplicate heartbeat request")]
+    DuplicateHeartbeat,
+
+    /// The ticket engine task is no longer running
+    #[error("Ticket engine stopped")]
+    EngineStopped,
+
+    /// A string or list doesn't fit in the single length byte the protocol gives it
+    #[error("{0} is too long to be sent")]
+    MessageTooLong(&'static str),
+
+    /// A dispatcher's queue filled up and the slow dispatcher policy is to drop it
+    #[error("Too slow to keep up with the tickets")]
+    SlowDispatcher,
+
     #[error("Client disconnected")]
     DisconnectedClient,
 
+    /// A startup setting could not be understood
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    /// The durable storage backend failed
+    #[error(transparent)]
+    StorageError(#[from] rusqlite::Error),
+
     /// Represents all other cases of `std::io::Error`.
     #[error(transparent)]
     IOError(#[from] std::io::Error),
@@ -1,12 +1,12 @@
-use log::{error, info};
+use log::error;
 
-use speed_daemon::{errors::SpeedDaemonError, message::InboundMessageType, state::Db};
+use crate::{errors::SpeedDaemonError, message::InboundMessageType, state::Db};
 use std::net::SocketAddr;
 
 pub async fn handle_i_am_camera(
     client_addr: &SocketAddr,
     new_camera: InboundMessageType,
-    mut shared_db: Db,
+    shared_db: Db,
 ) -> anyhow::Result<(), SpeedDaemonError> {
     // info!("Adding camera: {:?} to client {}", new_camera, client_addr);
 
@@ -1,16 +1,14 @@
 use std::net::SocketAddr;
 
+use crate::{errors::SpeedDaemonError, message::OutboundMessageType, state::Db, types::Road};
 use log::{error, info};
-use speed_daemon::{
-    errors::SpeedDaemonError, message::OutboundMessageType, state::Db, types::Road,
-};
 use tokio::sync::mpsc;
 
 pub async fn handle_i_am_dispatcher(
     roads: Vec<Road>,
     client_addr: &SocketAddr,
     tx: &mpsc::Sender<OutboundMessageType>,
-    mut shared_db: Db,
+    shared_db: Db,
 ) -> anyhow::Result<(), SpeedDaemonError> {
     info!("From {client_addr} adding a dispatcher for roads {roads:?}");
 
@@ -34,10 +32,14 @@ pub async fn handle_i_am_dispatcher(
     for road in roads.iter() {
         // for every road this dispatcher is responsible for, add the corresponding tx reference
         // info!("Adding dispatcher {} for road {}", client_addr, road);
-        let tx = tx.clone();
+        let pending_tickets = shared_db
+            .add_ticket_dispatcher(*road, *client_addr, tx.clone())
+            .await;
+
+        // deliver whatever was issued for this road before any dispatcher was around
         shared_db
-            .add_ticket_dispatcher(*road, *client_addr, tx)
-            .await
+            .deliver_pending_tickets(pending_tickets, tx)
+            .await?;
     }
     Ok(())
 }
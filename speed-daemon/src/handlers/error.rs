@@ -1,14 +1,12 @@
-use speed_daemon::{errors::SpeedDaemonError, message::OutboundMessageType};
+use crate::{errors::SpeedDaemonError, message::OutboundMessageType};
 use tokio::sync::mpsc;
 
-pub fn handle_error(
+/// Queues an Error frame for the client. The writer manager closes the connection once it's written.
+pub async fn handle_error(
     error_message: String,
-    tx: mpsc::Sender<OutboundMessageType>,
+    tx: &mpsc::Sender<OutboundMessageType>,
 ) -> anyhow::Result<(), SpeedDaemonError> {
-    tokio::spawn(async move {
-        tx.send(OutboundMessageType::Error(error_message))
-            .await
-            .expect("Unable to send error message");
-    });
-    Ok(())
+    tx.send(OutboundMessageType::Error(error_message))
+        .await
+        .map_err(|_| SpeedDaemonError::DisconnectedClient)
 }
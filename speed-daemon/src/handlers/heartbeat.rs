@@ -1,24 +1,38 @@
-use speed_daemon::message::OutboundMessageType;
+use crate::{errors::SpeedDaemonError, message::OutboundMessageType};
 use tokio::sync::mpsc;
-use tokio::task;
-// use tokio::time::sleep;
-use tokio::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
 
+/// Starts sending heartbeats every `interval` deciseconds on this connection.
+///
+/// The spawned task is stored in `heartbeat`, which `process` aborts once the client disconnects.
+/// A client may only ask once, so a second request is an error even if the first one was for
+/// an interval of 0, i.e. no heartbeats at all.
 pub async fn handle_want_hearbeat(
     interval: u32,
+    heartbeat: &mut Option<JoinHandle<()>>,
     tx: mpsc::Sender<OutboundMessageType>,
-) -> anyhow::Result<()> {
-    let interval = interval as f32 / 10.0;
-    task::spawn_blocking(move || {
+) -> anyhow::Result<(), SpeedDaemonError> {
+    if heartbeat.is_some() {
+        return Err(SpeedDaemonError::DuplicateHeartbeat);
+    }
+
+    *heartbeat = Some(tokio::spawn(async move {
+        // if interval is 0 then no heartbeat
+        if interval == 0 {
+            return;
+        }
+
+        let mut ticker = time::interval(Duration::from_millis(interval as u64 * 100));
         loop {
-            tx.blocking_send(OutboundMessageType::Heartbeat)
-                .expect("Unable to send heartbeat");
+            ticker.tick().await;
 
-            // sleep(Duration::from_millis(interval as u64)).await;
-            // let mut tick_interval = time::interval(Duration::from_secs_f32(interval));
-            std::thread::sleep(Duration::from_secs_f32(interval));
+            // the writer manager is gone, so is the client
+            if tx.send(OutboundMessageType::Heartbeat).await.is_err() {
+                break;
+            }
         }
-    });
+    }));
 
     Ok(())
 }
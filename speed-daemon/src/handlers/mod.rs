@@ -1,11 +1,11 @@
+mod camera;
 mod dispatcher;
+mod error;
 mod heartbeat;
-mod camera;
 mod plate;
-mod error;
 
+pub use camera::handle_i_am_camera;
 pub use dispatcher::handle_i_am_dispatcher;
+pub use error::handle_error;
 pub use heartbeat::handle_want_hearbeat;
-pub use camera::handle_i_am_camera;
 pub use plate::handle_plate;
-pub use error::handle_error;
\ No newline at end of file
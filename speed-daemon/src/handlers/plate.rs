@@ -1,11 +1,11 @@
-use log::{error, info};
-use speed_daemon::{
+use crate::{
+    engine::TicketEngine,
     errors::SpeedDaemonError,
     message::InboundMessageType,
     state::Db,
     types::{Plate, PlateRoadStruct, Timestamp, TimestampCameraStruct},
 };
-use tokio::sync::mpsc;
+use log::error;
 
 use std::net::SocketAddr;
 
@@ -13,7 +13,7 @@ pub async fn handle_plate(
     client_addr: &SocketAddr,
     new_plate: Plate,
     new_timestamp: Timestamp,
-    plate_tx: mpsc::Sender<PlateRoadStruct>,
+    ticket_engine: &TicketEngine,
     shared_db: Db,
 ) -> anyhow::Result<(), SpeedDaemonError> {
     // Get the camera that reported this plate
@@ -41,16 +41,13 @@ pub async fn handle_plate(
 
         // info!("Adding {:?} {:?}", new_plate_road, new_ts_camera);
 
-        // add the newly observed plate:road combo to the shared db
+        // record it right away, so a duplicate is reported to the camera that sent it
         shared_db
-            .add_plate_road_timestamp_camera(new_plate_road.clone(), new_ts_camera)
-            .await;
-
-        // send it off to the ticket_manager for processing
-        plate_tx
-            .send(new_plate_road)
-            .await
-            .expect("Unable to send new plate");
+            .add_plate_road_timestamp_camera(new_plate_road.clone(), new_ts_camera.clone())
+            .await?;
+
+        // then send it off to the ticket engine, which checks it for tickets
+        ticket_engine.submit(new_plate_road, new_ts_camera).await?;
     } else {
         // It wasn't a camera that reported the plate!
         error!("Plate message did not come from camera {client_addr}");
@@ -1,6 +1,19 @@
-pub mod errors;
+pub mod admin;
+pub mod client;
 pub mod codec;
+pub mod config;
+pub mod engine;
+pub mod errors;
+mod handlers;
 pub mod message;
+pub mod metrics;
 pub mod parsers;
-pub mod types;
+pub mod policy;
+pub mod queue;
+pub mod server;
+pub mod snapshot;
 pub mod state;
+pub mod storage;
+pub mod ticket;
+pub mod types;
+pub mod validation;
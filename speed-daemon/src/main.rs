@@ -1,258 +1,178 @@
 use speed_daemon::{
-    codec::MessageCodec,
-    errors::SpeedDaemonError,
-    message::{InboundMessageType, OutboundMessageType},
+    admin,
+    config::{Config, StorageConfig},
+    engine::TicketEngine,
+    queue::QueueDepths,
+    server::{self, Shutdown},
     state::Db,
-    types::PlateRoadStruct,
+    storage::MemoryStorage,
 };
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc,
-};
-
-use env_logger::Env;
-use log::{error, info};
-use tokio_util::codec::{FramedRead, FramedWrite};
-
-use futures::sink::SinkExt;
-use futures::StreamExt;
-
-mod handlers;
+use futures::future::try_join_all;
+use tokio::{net::TcpListener, runtime, signal, time};
 
-use handlers::{handle_i_am_camera, handle_i_am_dispatcher, handle_want_hearbeat};
+use log::{error, info, warn};
 
-use crate::handlers::{handle_error, handle_plate};
+fn main() -> anyhow::Result<()> {
+    // Flags, environment and config file, see `speed-daemon --help`.
+    let config = Config::load()?;
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() -> anyhow::Result<()> {
     // console_subscriber::init();
-    // Setup the logging framework
-    let env = Env::default()
-        .filter_or("LOG_LEVEL", "info")
-        .write_style_or("LOG_STYLE", "always");
+    // Setup the logging framework. RUST_LOG and RUST_LOG_STYLE are deliberately not consulted,
+    // LOG_LEVEL and LOG_STYLE already went into the config with the usual precedence.
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .parse_write_style(&config.log_style)
+        .init();
+
+    let mut runtime = runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = config.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
 
-    env_logger::init_from_env(env);
+    runtime.build()?.block_on(run(config))
+}
 
+async fn run(config: Config) -> anyhow::Result<()> {
     info!("Starting the speed daemon server.");
 
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080);
+    let queues = config.queues;
+    let shards = config.shards;
+
+    // Observations and tickets live in memory only, unless SQLite storage is configured.
+    let shared_db = match &config.storage {
+        StorageConfig::Sqlite(path) => {
+            info!("Using SQLite storage at {}", path.display());
+            Db::with_sqlite(path, shards, queues.max_pending_tickets).await?
+        }
+        StorageConfig::Memory => {
+            info!("Using in-memory storage.");
+            Db::with_storage(MemoryStorage::with_shards(shards))
+        }
+    };
 
-    let shared_db = Db::new();
+    let shared_db = shared_db
+        .with_policies(config.ticket_policies)
+        .with_slow_dispatcher(queues.slow_dispatcher)
+        .with_retention(config.retention);
 
-    // Bind a TCP listener to the socket address.
-    //
-    // Note that this is the Tokio TcpListener, which is fully async.
-    let listener = TcpListener::bind(&addr).await?;
+    // Old observations are evicted in the background if the retention policy asks for it.
+    shared_db.spawn_sweeper();
 
-    info!("Server running on {}", addr);
+    // Tickets left pending by a previous run are flushed once a dispatcher for their road connects.
+    let depths = shared_db.queue_depths().await;
+    let recovered = depths.pending_tickets + depths.spilled_tickets;
+    if recovered > 0 {
+        info!("Recovered {} undelivered tickets", recovered);
+    }
+
+    // One engine computes and routes the tickets for every camera, a worker per shard.
+    let ticket_engine = TicketEngine::spawn(shared_db.clone(), queues.observation_capacity, shards);
 
-    loop {
-        // Asynchronously wait for an inbound TcpStream.
-        let (stream, addr) = listener.accept().await?;
-        let shared_db_main = shared_db.clone();
+    if let Some(report_interval) = queues.report_interval {
+        let shared_db_report = shared_db.clone();
+        let ticket_engine_report = ticket_engine.clone();
 
-        // Spawn our handler to be run asynchronously.
         tokio::spawn(async move {
-            // info!("Accepted connection from {}", addr);
-            if let Err(e) = process(stream, addr, shared_db_main).await {
-                error!("Error: {:?}", e);
+            let mut ticker = time::interval(report_interval);
+            loop {
+                ticker.tick().await;
+
+                let depths = shared_db_report.queue_depths().await;
+                let depths = QueueDepths {
+                    observations: ticket_engine_report.queue_depth(),
+                    ..depths
+                };
+                if !depths.is_idle() {
+                    info!("Queue depths: {}", depths);
+                }
             }
         });
     }
-}
-
-async fn process(
-    stream: TcpStream,
-    addr: SocketAddr,
-    shared_db: Db,
-) -> anyhow::Result<(), SpeedDaemonError> {
-    // info!("Processing stream from {}", addr);
-    let (client_reader, client_writer) = stream.into_split();
 
-    let mut client_reader = FramedRead::new(client_reader, MessageCodec::new());
-    let mut client_writer = FramedWrite::new(client_writer, MessageCodec::new());
-
-    // The mpsc channel is used to send commands to the task managing the client connection.
-    // The multi-producer capability allows messages to be sent from many tasks.
-    // Creating the channel returns two values, a sender and a receiver.
-    // The two handles are used separately. They may be moved to different tasks.
+    // Bind a TCP listener to every socket address.
     //
-    // NOTE: The channel is created with a certain capacity.
-    // If messages are sent faster than they are received, the channel will store them.
-    // Once the N messages are stored in the channel,
-    // calling send(...).await will go to sleep until a message has been removed by the receiver.
-    let (tx, mut rx) = mpsc::channel::<OutboundMessageType>(819600);
-
-    // Spawn off a writer manager loop.
-    // In order to send a message back to the clients, all threads must use mpsc channel to publish data.
-    // The manager will then proxy the data and send it on behalf of threads.
-    let manager = tokio::spawn(async move {
-        // Start receiving messages from the channel by calling the recv method of the Receiver endpoint.
-        // This method blocks until a message is received.
-        while let Some(msg) = rx.recv().await {
-            // info!("Writer manager: sending {msg:?} to {addr}");
-
-            client_writer.send(msg).await?
-        }
-        Ok::<(), SpeedDaemonError>(())
-    });
-
-    let (ticket_tx, mut ticket_rx) = mpsc::channel::<OutboundMessageType>(8192000);
-
-    // Clone the handle to the shared state.
-    let shared_db_main = shared_db.clone();
+    // Note that this is the Tokio TcpListener, which is fully async.
+    let mut listeners = Vec::with_capacity(config.listen.len());
+    for addr in &config.listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Server running on {}", listener.local_addr()?);
+        listeners.push(listener);
+    }
 
-    // NOTE: These are cheap clones, pointers only.
-    let ticket_tx_queue = ticket_tx.clone();
+    // The admin endpoint keeps answering while the daemon shuts down.
+    if let Some(addr) = config.admin_listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!(
+            "Admin endpoint on http://{0}/state and http://{0}/metrics",
+            listener.local_addr()?
+        );
 
-    // Spawn off a ticket manager loop.
-    let ticket_manager = tokio::spawn(async move {
-        // Start receiving messages from the channel by calling the recv method of the Receiver endpoint.
-        // This method blocks until a message is received.
-        while let Some(ticket) = ticket_rx.recv().await {
-            if let OutboundMessageType::Ticket {
-                plate: _,
-                road,
-                mile1: _,
-                timestamp1: _,
-                mile2: _,
-                timestamp2: _,
-                speed: _,
-            } = ticket
-            {
-                if let Some(dispatcher_tx) = shared_db_main.get_ticket_dispatcher(&road).await {
-                    // info!("Ticket manager received {:?}", ticket);
-                    send_ticket_to_dispatcher(ticket, dispatcher_tx);
-                } else {
-                    // warn!("No dispatcher found, sending the ticket back");
-                    ticket_tx_queue
-                        .send(ticket)
-                        .await
-                        .expect("Unable to send ticket");
-                }
+        let shared_db_admin = shared_db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::run(listener, shared_db_admin).await {
+                error!("Admin endpoint stopped: {}", e);
             }
-        }
-        Ok::<(), SpeedDaemonError>(())
-    });
-
-    let (plate_tx, mut plate_rx) = mpsc::channel::<PlateRoadStruct>(8192000);
-    // this channel is for plate handler -> plate ticket checker
-    let plate_tx_queue = ticket_tx.clone();
-
-    let shared_db_plate = shared_db.clone();
-
-    // This receives messages from the plate_handler. Checks each one to see if we need to generate at ticket.
-    // Sends a ticket to the ticket dispatcher if yes.
-    let plate_manager = tokio::spawn(async move {
-        while let Some(new_plate_road) = plate_rx.recv().await {
-            if let Some(tickets) = shared_db_plate.get_ticket_for_plate(&new_plate_road).await {
-                // info!(
-                //     "Plate manager forwarding tickets {:?} to ticket manager",
-                //     tickets
-                // );
+        });
+    }
 
-                for ticket in tickets.iter() {
-                    // Send the ticket to the ticket dispatcher
-                    plate_tx_queue
-                        .send(ticket.to_owned())
-                        .await
-                        .expect("Unable to send ticket");
-                }
-            }
+    let shutdown = Shutdown::new();
+    let servers = try_join_all(listeners.into_iter().map(|listener| {
+        server::run(
+            listener,
+            shared_db.clone(),
+            ticket_engine.clone(),
+            queues.client_capacity,
+            shutdown.clone(),
+        )
+    }));
+
+    // Serve until a server fails or the daemon is asked to stop.
+    tokio::select! {
+        served = servers => {
+            served?;
         }
-        Ok::<(), SpeedDaemonError>(())
-    });
-
-    while let Some(message) = client_reader.next().await {
-        // info!("From {}: {:?}", addr, message);
-
-        match message {
-            Ok(InboundMessageType::Plate { plate, timestamp }) => {
-                match handle_plate(&addr, plate, timestamp, plate_tx.clone(), shared_db.clone())
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("{} from {:?}", e, addr);
-                        let tx_error = tx.clone();
-                        handle_error(e.to_string(), tx_error)?;
-                    }
-                }
-            }
-
-            Ok(InboundMessageType::WantHeartbeat { interval }) => {
-                // if interal is 0 then no heartbeat
-                if interval == 0 {
-                    // info!("Interval is 0, no heartbeat.")
-                } else {
-                    let tx_heartbeat = tx.clone();
-                    handle_want_hearbeat(interval, tx_heartbeat)
-                        .await
-                        .expect("Unable to heartbeat");
-                }
-            }
+        signalled = shutdown_signal() => signalled?,
+    }
 
-            Ok(InboundMessageType::IAmCamera { road, mile, limit }) => {
-                let new_camera = InboundMessageType::IAmCamera { road, mile, limit };
-                match handle_i_am_camera(&addr, new_camera, shared_db.clone()).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        // let err_message = String::from("Duplicate IAmCamera message");
-                        error!("{} from {:?}", e, addr);
-                        let tx_error = tx.clone();
-                        handle_error(e.to_string(), tx_error)?;
-                    }
-                }
-            }
+    // A second signal means the operator doesn't want to wait for the drain.
+    tokio::select! {
+        _ = shutdown.run(&ticket_engine, config.shutdown_timeout) => {}
+        _ = shutdown_signal() => warn!("Signalled again, exiting right away."),
+    }
 
-            Ok(InboundMessageType::IAmDispatcher { roads }) => {
-                // info!("Dispatcher detected at address {}", addr);
-                match handle_i_am_dispatcher(roads, &addr, &tx, shared_db.clone()).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        // let err_message = String::from("Duplicate IAmDispatcher message");
-                        error!("{} from {:?}", e, addr);
-                        let tx_error = tx.clone();
-                        handle_error(e.to_string(), tx_error)?;
-                    }
-                }
-            }
-            Err(e) => {
-                // let err_message = String::from("Unknown message detected");
-                // error!("{}", err_message);
-                let tx_error = tx.clone();
-                handle_error(e.to_string(), tx_error)?;
+    let depths = shared_db.queue_depths().await;
+    let undelivered = depths.pending_tickets + depths.spilled_tickets;
+    if undelivered > 0 {
+        match config.storage {
+            StorageConfig::Sqlite(_) => {
+                info!(
+                    "{} undelivered tickets are kept for the next run",
+                    undelivered
+                )
             }
+            StorageConfig::Memory => warn!("{} undelivered tickets are lost", undelivered),
         }
     }
 
-    if let Err(e) = manager.await.expect("Unable to await msg manager") {
-        error!("Error from the tx manager: {}", e)
-    }
+    info!("Speed daemon stopped.");
+    Ok(())
+}
 
-    if let Err(e) = ticket_manager
-        .await
-        .expect("Unable to await ticket manager")
-    {
-        error!("Error from the tx manager: {}", e)
-    }
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
 
-    if plate_manager
-        .await
-        .expect("Unable to await msg manager")
-        .is_err()
-    {
-        // error!("Error from the tx manager: {}", e)
+    tokio::select! {
+        interrupted = signal::ctrl_c() => interrupted,
+        _ = terminate.recv() => Ok(()),
     }
-
-    Ok(())
 }
 
-fn send_ticket_to_dispatcher(ticket: OutboundMessageType, tx: mpsc::Sender<OutboundMessageType>) {
-    // info!("Sending {:?} to writer manager ", ticket);
-    tokio::spawn(async move {
-        tx.send(ticket).await.expect("Unable to send ticket");
-    });
+/// Resolves on the first Ctrl-C, there's no SIGTERM outside of unix.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    signal::ctrl_c().await
 }
@@ -18,7 +18,7 @@ impl Default for InboundMessageType {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum OutboundMessageType {
     Heartbeat,
 
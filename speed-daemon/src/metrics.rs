@@ -0,0 +1,375 @@
+//! Counters and gauges of what the server does, rendered in the Prometheus text format.
+//!
+//! Every [`Db`](crate::state::Db) holds a [`Metrics`], connections and the ticket engine record
+//! into it and the admin endpoint serves it at `/metrics`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{
+    message::{InboundMessageType, OutboundMessageType},
+    types::Road,
+};
+
+/// Upper bounds, in seconds, of the ticket delivery latency buckets. A ticket for a road
+/// without a dispatcher waits until one connects, hence the long tail.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0, 3600.0,
+];
+
+/// Most tickets waiting for delivery whose issue time is remembered. Beyond that the oldest
+/// are forgotten, so a road nobody dispatches for can't grow the metrics without bound.
+const MAX_TRACKED_TICKETS: usize = 10_000;
+
+/// What a client said it is.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ClientKind {
+    /// Hasn't sent IAmCamera or IAmDispatcher yet.
+    #[default]
+    Unidentified,
+    Camera,
+    Dispatcher,
+}
+
+impl ClientKind {
+    const ALL: [ClientKind; 3] = [
+        ClientKind::Unidentified,
+        ClientKind::Camera,
+        ClientKind::Dispatcher,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ClientKind::Unidentified => "unidentified",
+            ClientKind::Camera => "camera",
+            ClientKind::Dispatcher => "dispatcher",
+        }
+    }
+}
+
+/// Labels of the inbound message types, indexed like `messages_decoded`.
+const MESSAGE_TYPES: [&str; 4] = ["plate", "want_heartbeat", "i_am_camera", "i_am_dispatcher"];
+
+fn message_index(message: &InboundMessageType) -> usize {
+    match message {
+        InboundMessageType::Plate { .. } => 0,
+        InboundMessageType::WantHeartbeat { .. } => 1,
+        InboundMessageType::IAmCamera { .. } => 2,
+        InboundMessageType::IAmDispatcher { .. } => 3,
+    }
+}
+
+/// Everything that is counted. Recording never waits on anything but a short,
+/// synchronous critical section, so it's fine from any task.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections_accepted: AtomicU64,
+    /// Open connections, indexed like `ClientKind::ALL`. Signed so a gauge can't wrap around
+    /// should a decrement ever overtake its increment.
+    connections: [AtomicI64; 3],
+    messages_decoded: [AtomicU64; 4],
+    parse_failures: AtomicU64,
+    heartbeats_sent: AtomicU64,
+    tickets: Mutex<TicketMetrics>,
+}
+
+#[derive(Debug, Default)]
+struct TicketMetrics {
+    issued: BTreeMap<Road, u64>,
+    delivered: BTreeMap<Road, u64>,
+    issued_at: IssueTimes,
+    delivery_latency: Histogram,
+}
+
+/// When each ticket that hasn't been delivered yet was issued, for at most
+/// [`MAX_TRACKED_TICKETS`] tickets. Tickets recovered from a previous run or forgotten
+/// meanwhile aren't in here, their delivery latency is unknown.
+#[derive(Debug, Default)]
+struct IssueTimes {
+    /// The issue time of each ticket, along with its position in `order`.
+    tickets: HashMap<OutboundMessageType, (u64, Instant)>,
+    /// The tracked tickets by when they were issued.
+    order: BTreeMap<u64, OutboundMessageType>,
+    next: u64,
+}
+
+impl IssueTimes {
+    fn insert(&mut self, ticket: OutboundMessageType) {
+        if self.tickets.len() >= MAX_TRACKED_TICKETS && !self.tickets.contains_key(&ticket) {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.tickets.remove(&oldest);
+            }
+        }
+
+        let position = self.next;
+        self.next += 1;
+        if let Some((previous, _)) = self
+            .tickets
+            .insert(ticket.clone(), (position, Instant::now()))
+        {
+            self.order.remove(&previous);
+        }
+        self.order.insert(position, ticket);
+    }
+
+    fn remove(&mut self, ticket: &OutboundMessageType) -> Option<Instant> {
+        let (position, issued_at) = self.tickets.remove(ticket)?;
+        self.order.remove(&position);
+        Some(issued_at)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative. The last one counts what's beyond every bound.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// A client connected, it's unidentified until it says what it is.
+    pub fn connection_opened(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.gauge(ClientKind::Unidentified)
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An unidentified client registered as a camera or a dispatcher.
+    pub fn client_identified(&self, kind: ClientKind) {
+        self.gauge(ClientKind::Unidentified)
+            .fetch_sub(1, Ordering::Relaxed);
+        self.gauge(kind).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self, kind: ClientKind) {
+        self.gauge(kind).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn gauge(&self, kind: ClientKind) -> &AtomicI64 {
+        &self.connections[kind as usize]
+    }
+
+    pub fn message_decoded(&self, message: &InboundMessageType) {
+        self.messages_decoded[message_index(message)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Bytes from a client that aren't a message at all.
+    pub fn parse_failure(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts what has been written to a client: heartbeats, and tickets, which are delivered then.
+    pub fn message_sent(&self, message: &OutboundMessageType) {
+        match message {
+            OutboundMessageType::Heartbeat => {
+                self.heartbeats_sent.fetch_add(1, Ordering::Relaxed);
+            }
+            OutboundMessageType::Ticket { road, .. } => {
+                let mut tickets = self.tickets.lock().expect("metrics lock poisoned");
+
+                *tickets.delivered.entry(*road).or_default() += 1;
+                if let Some(issued_at) = tickets.issued_at.remove(message) {
+                    tickets
+                        .delivery_latency
+                        .observe(issued_at.elapsed().as_secs_f64());
+                }
+            }
+            OutboundMessageType::Error(_) => {}
+        }
+    }
+
+    /// The ticket engine issued a new ticket, delivery latency is measured from here.
+    pub fn ticket_issued(&self, ticket: &OutboundMessageType) {
+        let OutboundMessageType::Ticket { road, .. } = ticket else {
+            return;
+        };
+        let mut tickets = self.tickets.lock().expect("metrics lock poisoned");
+
+        *tickets.issued.entry(*road).or_default() += 1;
+        tickets.issued_at.insert(ticket.clone());
+    }
+
+    /// Renders every metric in the Prometheus text exposition format, along with the
+    /// number of tickets `pending` on each road, which the storage backend knows best.
+    pub fn render(&self, pending: &HashMap<Road, usize>) -> String {
+        let mut out = String::new();
+        self.write(&mut out, pending)
+            .expect("writing to a String never fails");
+        out
+    }
+
+    fn write(&self, out: &mut String, pending: &HashMap<Road, usize>) -> fmt::Result {
+        header(
+            out,
+            "speed_daemon_connections_accepted_total",
+            "counter",
+            "Client connections accepted.",
+        )?;
+        writeln!(
+            out,
+            "speed_daemon_connections_accepted_total {}",
+            self.connections_accepted.load(Ordering::Relaxed)
+        )?;
+
+        header(
+            out,
+            "speed_daemon_connections",
+            "gauge",
+            "Open client connections by what the client said it is.",
+        )?;
+        for kind in ClientKind::ALL {
+            writeln!(
+                out,
+                "speed_daemon_connections{{client=\"{}\"}} {}",
+                kind.label(),
+                self.gauge(kind).load(Ordering::Relaxed).max(0)
+            )?;
+        }
+
+        header(
+            out,
+            "speed_daemon_messages_decoded_total",
+            "counter",
+            "Messages received from clients by type.",
+        )?;
+        for (label, count) in MESSAGE_TYPES.iter().zip(&self.messages_decoded) {
+            writeln!(
+                out,
+                "speed_daemon_messages_decoded_total{{type=\"{}\"}} {}",
+                label,
+                count.load(Ordering::Relaxed)
+            )?;
+        }
+
+        header(
+            out,
+            "speed_daemon_parse_failures_total",
+            "counter",
+            "Clients disconnected for sending something that isn't a message.",
+        )?;
+        writeln!(
+            out,
+            "speed_daemon_parse_failures_total {}",
+            self.parse_failures.load(Ordering::Relaxed)
+        )?;
+
+        header(
+            out,
+            "speed_daemon_heartbeats_sent_total",
+            "counter",
+            "Heartbeats written to clients.",
+        )?;
+        writeln!(
+            out,
+            "speed_daemon_heartbeats_sent_total {}",
+            self.heartbeats_sent.load(Ordering::Relaxed)
+        )?;
+
+        let tickets = self.tickets.lock().expect("metrics lock poisoned");
+
+        header(
+            out,
+            "speed_daemon_tickets_issued_total",
+            "counter",
+            "Tickets issued by road.",
+        )?;
+        for (road, count) in &tickets.issued {
+            writeln!(
+                out,
+                "speed_daemon_tickets_issued_total{{road=\"{road}\"}} {count}"
+            )?;
+        }
+
+        header(
+            out,
+            "speed_daemon_tickets_delivered_total",
+            "counter",
+            "Tickets written to a dispatcher by road.",
+        )?;
+        for (road, count) in &tickets.delivered {
+            writeln!(
+                out,
+                "speed_daemon_tickets_delivered_total{{road=\"{road}\"}} {count}"
+            )?;
+        }
+
+        // Roads that had tickets once keep reporting 0 instead of vanishing.
+        let roads: BTreeSet<Road> = tickets
+            .issued
+            .keys()
+            .chain(tickets.delivered.keys())
+            .chain(pending.keys())
+            .copied()
+            .collect();
+        header(
+            out,
+            "speed_daemon_tickets_pending",
+            "gauge",
+            "Tickets waiting for a dispatcher for their road.",
+        )?;
+        for road in roads {
+            writeln!(
+                out,
+                "speed_daemon_tickets_pending{{road=\"{road}\"}} {}",
+                pending.get(&road).copied().unwrap_or_default()
+            )?;
+        }
+
+        let latency = &tickets.delivery_latency;
+        header(
+            out,
+            "speed_daemon_ticket_delivery_seconds",
+            "histogram",
+            "Time from issuing a ticket to writing it to a dispatcher.",
+        )?;
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+            cumulative += count;
+            writeln!(
+                out,
+                "speed_daemon_ticket_delivery_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            )?;
+        }
+        cumulative += latency.buckets[LATENCY_BUCKETS.len()];
+        writeln!(
+            out,
+            "speed_daemon_ticket_delivery_seconds_bucket{{le=\"+Inf\"}} {cumulative}"
+        )?;
+        writeln!(
+            out,
+            "speed_daemon_ticket_delivery_seconds_sum {}",
+            latency.sum
+        )?;
+        writeln!(
+            out,
+            "speed_daemon_ticket_delivery_seconds_count {cumulative}"
+        )
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} {kind}")
+}
@@ -9,12 +9,10 @@ use nom::{
 // use hex;
 // use log::info;
 
-use crate::message::InboundMessageType;
-
-fn parse_plate(input: &[u8]) -> nom::IResult<&[u8], InboundMessageType> {
-    // 0x20: Plate (Client->Server)
-    let (input, _) = tag([0x20])(input)?;
+use crate::message::{InboundMessageType, OutboundMessageType};
 
+/// Parses a protocol string: a length byte followed by that many bytes of text.
+fn parse_str(input: &[u8]) -> IResult<&[u8], String> {
     // Parse the length byte
     let (input, length) = be_u8(input)?;
 
@@ -22,10 +20,19 @@ fn parse_plate(input: &[u8]) -> nom::IResult<&[u8], InboundMessageType> {
     let (input, string_bytes) = take(length)(input)?;
 
     // Convert the bytes to a String
-    let plate = String::from_utf8(string_bytes.to_vec()).map_err(|_| {
+    let string = String::from_utf8(string_bytes.to_vec()).map_err(|_| {
         nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))
     })?;
 
+    Ok((input, string))
+}
+
+fn parse_plate(input: &[u8]) -> nom::IResult<&[u8], InboundMessageType> {
+    // 0x20: Plate (Client->Server)
+    let (input, _) = tag([0x20])(input)?;
+
+    let (input, plate) = parse_str(input)?;
+
     // timestamp: u32
     let (input, timestamp) = be_u32(input)?;
 
@@ -72,3 +79,49 @@ pub fn parse_message(input: &[u8]) -> IResult<&[u8], InboundMessageType> {
     // info!("Parser finished, inbound message: {:?}", message);
     Ok((input, message))
 }
+
+pub fn parse_error(input: &[u8]) -> IResult<&[u8], OutboundMessageType> {
+    // 0x10: Error (Server->Client)
+    let (input, _) = tag([0x10])(input)?;
+    let (input, msg) = parse_str(input)?;
+    Ok((input, OutboundMessageType::Error(msg)))
+}
+
+pub fn parse_ticket(input: &[u8]) -> IResult<&[u8], OutboundMessageType> {
+    // 0x21: Ticket (Server->Client)
+    let (input, _) = tag([0x21])(input)?;
+    let (input, plate) = parse_str(input)?;
+    let (input, road) = be_u16(input)?;
+    let (input, mile1) = be_u16(input)?;
+    let (input, timestamp1) = be_u32(input)?;
+    let (input, mile2) = be_u16(input)?;
+    let (input, timestamp2) = be_u32(input)?;
+    let (input, speed) = be_u16(input)?;
+    Ok((
+        input,
+        OutboundMessageType::Ticket {
+            plate,
+            road,
+            mile1,
+            timestamp1,
+            mile2,
+            timestamp2,
+            speed,
+        },
+    ))
+}
+
+pub fn parse_heartbeat(input: &[u8]) -> IResult<&[u8], OutboundMessageType> {
+    // 0x41: Heartbeat (Server->Client)
+    let (input, _) = tag([0x41])(input)?;
+    Ok((input, OutboundMessageType::Heartbeat))
+}
+
+/// Parses a message sent by the server, the counterpart of [`parse_message`] for clients.
+///
+/// # Errors
+///
+/// This function will return an error if none of the parsers match.
+pub fn parse_outbound_message(input: &[u8]) -> IResult<&[u8], OutboundMessageType> {
+    alt((parse_error, parse_ticket, parse_heartbeat))(input)
+}
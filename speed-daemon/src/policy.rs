@@ -0,0 +1,283 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    errors::SpeedDaemonError,
+    ticket::{day, AverageSpeed},
+    types::{Day, Limit, Road, Speed, Timestamp, TimestampCameraStruct},
+};
+
+/// How an average speed is rounded to whole mph before it's compared against the limit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Rounding {
+    #[default]
+    Nearest,
+    Down,
+    Up,
+}
+
+impl FromStr for Rounding {
+    type Err = SpeedDaemonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Rounding::Nearest),
+            "down" => Ok(Rounding::Down),
+            "up" => Ok(Rounding::Up),
+            other => Err(SpeedDaemonError::InvalidConfig(format!(
+                "unknown rounding mode {other:?}, expected nearest, down or up"
+            ))),
+        }
+    }
+}
+
+/// Decides which average speeds on a road get ticketed and what speed the ticket reports.
+///
+/// The default reproduces the plain spec behaviour: round to the nearest mph,
+/// ticket anything above the limit, report the rounded speed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TicketPolicy {
+    /// How far above the limit a car may go before it's ticketed, in hundredths of mph.
+    /// Compared against the exact average speed, not the rounded one.
+    pub tolerance: Speed,
+    pub rounding: Rounding,
+    /// Report the exact average speed in hundredths of mph instead of the rounded one.
+    pub exact_speed: bool,
+}
+
+impl TicketPolicy {
+    /// Returns the speed to put on the ticket, in hundredths of mph,
+    /// or `None` if the car doesn't deserve one. This is the only place the policy is applied.
+    pub fn ticket_speed(&self, average_speed: AverageSpeed, limit: Limit) -> Option<Speed> {
+        let rounded_mph = average_speed.mph(self.rounding);
+        if rounded_mph <= limit as u64 {
+            return None;
+        }
+
+        // The tolerance is checked against the exact speed, a fraction of a mph would
+        // otherwise be swallowed by the rounding.
+        let threshold = limit as u64 * 100 + self.tolerance as u64;
+        if average_speed.hundredths() <= threshold {
+            return None;
+        }
+
+        let reported = if self.exact_speed {
+            average_speed.hundredths()
+        } else {
+            rounded_mph * 100
+        };
+
+        // a Ticket can't carry more than 655.35 mph
+        Some(reported.min(Speed::MAX as u64) as Speed)
+    }
+}
+
+/// Parses a comma separated list of settings, e.g. `tolerance=0.5,rounding=down,exact=true`.
+/// Settings that are left out keep their default.
+impl FromStr for TicketPolicy {
+    type Err = SpeedDaemonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = TicketPolicy::default();
+
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid =
+                || SpeedDaemonError::InvalidConfig(format!("invalid setting {setting:?}"));
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+
+            match key.trim() {
+                "tolerance" => {
+                    policy.tolerance = parse_hundredths(value.trim()).ok_or_else(invalid)?
+                }
+                "rounding" => policy.rounding = value.trim().parse()?,
+                "exact" => policy.exact_speed = value.trim().parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+/// Parses a decimal number of mph with at most two decimals, e.g. `0.5`, into hundredths of mph.
+fn parse_hundredths(value: &str) -> Option<Speed> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    if fraction.len() > 2 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let whole: Speed = whole.parse().ok()?;
+    let fraction: Speed = format!("{fraction:0<2}").parse().ok()?;
+
+    whole.checked_mul(100)?.checked_add(fraction)
+}
+
+/// The ticket policy of every road: a default plus per-road overrides.
+#[derive(Clone, Debug, Default)]
+pub struct TicketPolicies {
+    pub default: TicketPolicy,
+    pub roads: HashMap<Road, TicketPolicy>,
+}
+
+impl TicketPolicies {
+    /// Reads the per-road overrides from `TICKET_POLICY_<road>` environment variables.
+    pub fn roads_from_env() -> Result<HashMap<Road, TicketPolicy>, SpeedDaemonError> {
+        let mut roads = HashMap::new();
+
+        for (key, value) in env::vars() {
+            if let Some(road) = key.strip_prefix("TICKET_POLICY_") {
+                let road = road.parse().map_err(|_| {
+                    SpeedDaemonError::InvalidConfig(format!("{key} does not name a valid road"))
+                })?;
+                roads.insert(road, value.parse()?);
+            }
+        }
+
+        Ok(roads)
+    }
+
+    pub fn for_road(&self, road: Road) -> TicketPolicy {
+        self.roads.get(&road).copied().unwrap_or(self.default)
+    }
+}
+
+/// Which observations may be forgotten, so a long-running daemon's memory stays flat.
+///
+/// The default keeps every observation forever. Evicted observations can no longer be paired with
+/// ones that arrive later, so the limits trade late, out-of-order plates for memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    /// Observations taken more than this many seconds before the newest timestamp seen on their
+    /// road are evicted, along with the days before them that cars were ticketed for there.
+    pub max_age: Option<Timestamp>,
+    /// At most this many observations are kept per plate and road, the oldest are evicted first.
+    pub max_observations: Option<usize>,
+    /// Evict observations taken on a day the car was already ticketed for. Any pair including
+    /// one of them spans that day, so they can never lead to another ticket.
+    pub evict_ticketed_days: bool,
+    /// How often the sweeper applies the policy.
+    pub sweep_interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age: None,
+            max_observations: None,
+            evict_ticketed_days: false,
+            sweep_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Whether the policy never evicts anything, in which case there's no need for a sweeper.
+    pub fn keeps_everything(&self) -> bool {
+        self.max_age.is_none() && self.max_observations.is_none() && !self.evict_ticketed_days
+    }
+
+    /// Evicts the observations of one plate on one road that the policy doesn't keep and returns
+    /// their timestamps. `observations` must be sorted by timestamp, `newest` is the newest
+    /// timestamp seen on the road and `issued_days` the days this car was ticketed for.
+    pub fn evict(
+        &self,
+        observations: &mut Vec<TimestampCameraStruct>,
+        newest: Timestamp,
+        issued_days: Option<&HashSet<Day>>,
+    ) -> Vec<Timestamp> {
+        let mut evicted = Vec::new();
+
+        if self.evict_ticketed_days {
+            if let Some(issued_days) = issued_days {
+                observations.retain(|observation| {
+                    let keep = !issued_days.contains(&day(observation.timestamp));
+                    if !keep {
+                        evicted.push(observation.timestamp);
+                    }
+                    keep
+                });
+            }
+        }
+
+        if let Some(max_age) = self.max_age {
+            let oldest = newest.saturating_sub(max_age);
+            let expired =
+                observations.partition_point(|observation| observation.timestamp < oldest);
+            evicted.extend(
+                observations
+                    .drain(..expired)
+                    .map(|observation| observation.timestamp),
+            );
+        }
+
+        if let Some(max_observations) = self.max_observations {
+            let excess = observations.len().saturating_sub(max_observations);
+            evicted.extend(
+                observations
+                    .drain(..excess)
+                    .map(|observation| observation.timestamp),
+            );
+        }
+
+        evicted
+    }
+
+    /// The oldest day whose ticket records are kept, `None` if they're kept forever.
+    /// Every observation of an earlier day is older than `max_age` and evicted, so no
+    /// ticket covering that day can be issued again, barring plates that arrive that late.
+    pub fn oldest_kept_day(&self, newest: Timestamp) -> Option<Day> {
+        self.max_age
+            .map(|max_age| day(newest.saturating_sub(max_age)))
+    }
+
+    /// Forgets the days a car was ticketed for that are older than [`oldest_kept_day`](Self::oldest_kept_day)
+    /// and returns how many there were.
+    pub fn evict_days(&self, issued_days: &mut HashSet<Day>, newest: Timestamp) -> usize {
+        let Some(oldest) = self.oldest_kept_day(newest) else {
+            return 0;
+        };
+
+        let before = issued_days.len();
+        issued_days.retain(|day| *day >= oldest);
+        before - issued_days.len()
+    }
+}
+
+/// Parses a comma separated list of settings, e.g. `max_age=86400,max_observations=100,ticketed_days=true,interval=30`.
+/// Ages and the sweep interval are in seconds. Settings that are left out keep their default.
+impl FromStr for RetentionPolicy {
+    type Err = SpeedDaemonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = RetentionPolicy::default();
+
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid =
+                || SpeedDaemonError::InvalidConfig(format!("invalid setting {setting:?}"));
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+
+            match key.trim() {
+                "max_age" => policy.max_age = Some(value.parse().map_err(|_| invalid())?),
+                "max_observations" => {
+                    policy.max_observations = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "ticketed_days" => {
+                    policy.evict_ticketed_days = value.parse().map_err(|_| invalid())?
+                }
+                "interval" => match value.parse().map_err(|_| invalid())? {
+                    0 => return Err(invalid()),
+                    seconds => policy.sweep_interval = Duration::from_secs(seconds),
+                },
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(policy)
+    }
+}
@@ -0,0 +1,118 @@
+use std::{collections::HashMap, fmt, net::SocketAddr, str::FromStr, time::Duration};
+
+use crate::errors::SpeedDaemonError;
+
+/// What happens when a ticket is routed to a dispatcher whose outbound queue is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SlowDispatcherPolicy {
+    /// Disconnect the dispatcher and hand the ticket to another one, or keep it pending.
+    /// Whatever the dispatcher already has queued is still delivered, followed by an Error.
+    #[default]
+    Drop,
+    /// Wait until the dispatcher has room again. The ticket engine worker waits along with it,
+    /// and so, once its queue is full, do the cameras on every road that worker handles.
+    Block,
+}
+
+impl FromStr for SlowDispatcherPolicy {
+    type Err = SpeedDaemonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(SlowDispatcherPolicy::Drop),
+            "block" => Ok(SlowDispatcherPolicy::Block),
+            other => Err(SpeedDaemonError::InvalidConfig(format!(
+                "unknown slow dispatcher policy {other:?}, expected drop or block"
+            ))),
+        }
+    }
+}
+
+/// Sizes of every queue the server keeps, and what happens once one of them is full.
+///
+/// Cameras are always blocked when the observation queue is full, so a flood of plates
+/// slows the cameras sending it down instead of growing memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueueConfig {
+    /// Messages (tickets, heartbeats, the final error) waiting to be written to a single client.
+    pub client_capacity: usize,
+    /// Observations waiting for the ticket engine.
+    pub observation_capacity: usize,
+    pub slow_dispatcher: SlowDispatcherPolicy,
+    /// Undelivered tickets kept in memory. A durable backend spills the rest to disk,
+    /// the in-memory backend has nowhere to put them and keeps them all.
+    pub max_pending_tickets: usize,
+    /// How often the queue depths are logged, `None` to never log them.
+    pub report_interval: Option<Duration>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            client_capacity: 1024,
+            observation_capacity: 4096,
+            slow_dispatcher: SlowDispatcherPolicy::default(),
+            max_pending_tickets: 100_000,
+            report_interval: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Channels can't be created with a capacity of 0.
+    pub fn validate(&self) -> Result<(), SpeedDaemonError> {
+        if self.client_capacity == 0 || self.observation_capacity == 0 {
+            return Err(SpeedDaemonError::InvalidConfig(String::from(
+                "queue capacities must be at least 1",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// A snapshot of how full the server's queues are.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct QueueDepths {
+    /// Observations waiting for the ticket engine.
+    pub observations: usize,
+    /// Messages waiting to be written to each dispatcher.
+    pub dispatchers: HashMap<SocketAddr, usize>,
+    /// Undelivered tickets held in memory.
+    pub pending_tickets: usize,
+    /// Undelivered tickets that only live in durable storage.
+    pub spilled_tickets: usize,
+}
+
+impl QueueDepths {
+    /// The backlog of the dispatcher that is furthest behind.
+    pub fn deepest_dispatcher(&self) -> Option<(SocketAddr, usize)> {
+        self.dispatchers
+            .iter()
+            .max_by_key(|(_, depth)| **depth)
+            .map(|(addr, depth)| (*addr, *depth))
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.observations == 0
+            && self.pending_tickets == 0
+            && self.spilled_tickets == 0
+            && self.dispatchers.values().all(|depth| *depth == 0)
+    }
+}
+
+impl fmt::Display for QueueDepths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} observations queued, {} tickets pending ({} spilled to storage)",
+            self.observations, self.pending_tickets, self.spilled_tickets
+        )?;
+
+        if let Some((addr, depth)) = self.deepest_dispatcher() {
+            write!(f, ", deepest dispatcher queue {depth} ({addr})")?;
+        }
+
+        Ok(())
+    }
+}
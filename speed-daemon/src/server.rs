@@ -0,0 +1,331 @@
+use std::{iter, net::SocketAddr, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{self, Instant},
+};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+    task::TaskTracker,
+};
+
+use crate::{
+    codec::MessageCodec,
+    engine::TicketEngine,
+    errors::SpeedDaemonError,
+    handlers::{
+        handle_error, handle_i_am_camera, handle_i_am_dispatcher, handle_plate,
+        handle_want_hearbeat,
+    },
+    message::{InboundMessageType, OutboundMessageType},
+    metrics::ClientKind,
+    state::Db,
+    validation::validate,
+};
+
+/// How long connections get to park their undelivered tickets once the shutdown deadline is over.
+const ABORT_GRACE: Duration = Duration::from_secs(1);
+
+/// Coordinates a graceful shutdown of the servers sharing it and of their connections.
+///
+/// Shutting down goes in three steps: stop accepting clients and reading from them, let the
+/// ticket engine route the tickets of every observation it already has, then let dispatchers
+/// write out their queues and disconnect. Whatever a dispatcher couldn't write before the
+/// deadline is parked as a pending ticket, which a durable backend keeps for the next run.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    /// Stop accepting connections and reading from clients.
+    stop: CancellationToken,
+    /// Every ticket has been routed, dispatchers may flush their queues and disconnect.
+    drained: CancellationToken,
+    /// Out of time: stop writing to clients right away.
+    abort: CancellationToken,
+    connections: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Runs the shutdown sequence, giving up on slow clients after `deadline`.
+    pub async fn run(&self, ticket_engine: &TicketEngine, deadline: Duration) {
+        let deadline = Instant::now() + deadline;
+
+        info!(
+            "Shutting down, {} connections to drain.",
+            self.connections.len()
+        );
+        self.stop.cancel();
+        self.connections.close();
+
+        let drain = async {
+            ticket_engine.drain().await;
+            self.drained.cancel();
+            self.connections.wait().await;
+        };
+
+        if time::timeout_at(deadline, drain).await.is_err() {
+            warn!(
+                "Shutdown deadline reached, dropping {} connections.",
+                self.connections.len()
+            );
+            self.drained.cancel();
+            self.abort.cancel();
+
+            // Parking tickets doesn't wait on any client, it's quick unless storage is stuck.
+            if time::timeout(ABORT_GRACE, self.connections.wait())
+                .await
+                .is_err()
+            {
+                error!("Connections didn't stop in time, their queued tickets are lost.");
+            }
+        }
+    }
+}
+
+/// Accepts connections on `listener` until `shutdown` starts or accepting fails,
+/// serving each one on its own task.
+///
+/// Every client gets an outbound queue of `client_capacity` messages. The caller owns the
+/// shared state and the ticket engine, so the server can just as well run inside a test.
+pub async fn run(
+    listener: TcpListener,
+    shared_db: Db,
+    ticket_engine: TicketEngine,
+    client_capacity: usize,
+    shutdown: Shutdown,
+) -> Result<(), SpeedDaemonError> {
+    loop {
+        // Asynchronously wait for an inbound TcpStream.
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.stop.cancelled() => return Ok(()),
+        };
+        let shared_db_main = shared_db.clone();
+        let ticket_engine_main = ticket_engine.clone();
+        let shutdown_main = shutdown.clone();
+
+        // Spawn our handler to be run asynchronously.
+        shutdown.connections.spawn(async move {
+            // info!("Accepted connection from {}", addr);
+            if let Err(e) = process(
+                stream,
+                addr,
+                shared_db_main,
+                ticket_engine_main,
+                client_capacity,
+                shutdown_main,
+            )
+            .await
+            {
+                error!("Error: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn process(
+    stream: TcpStream,
+    addr: SocketAddr,
+    shared_db: Db,
+    ticket_engine: TicketEngine,
+    client_capacity: usize,
+    shutdown: Shutdown,
+) -> anyhow::Result<(), SpeedDaemonError> {
+    // info!("Processing stream from {}", addr);
+    shared_db.metrics().connection_opened();
+    let dropped = shared_db.track_client(addr);
+
+    let (client_reader, client_writer) = stream.into_split();
+
+    let mut client_reader = FramedRead::new(client_reader, MessageCodec::new());
+    let mut client_writer = FramedWrite::new(client_writer, MessageCodec::new());
+
+    // The mpsc channel is used to send commands to the task managing the client connection.
+    // The multi-producer capability allows messages to be sent from many tasks.
+    // Creating the channel returns two values, a sender and a receiver.
+    // The two handles are used separately. They may be moved to different tasks.
+    //
+    // NOTE: The channel is created with a certain capacity.
+    // If messages are sent faster than they are received, the channel will store them.
+    // Once the N messages are stored in the channel,
+    // calling send(...).await will go to sleep until a message has been removed by the receiver,
+    // unless the ticket router's SlowDispatcherPolicy says to give up on this client instead.
+    let (tx, mut rx) = mpsc::channel::<OutboundMessageType>(client_capacity);
+
+    // Spawn off a writer manager loop.
+    // In order to send a message back to the clients, all threads must use mpsc channel to publish data.
+    // The manager will then proxy the data and send it on behalf of threads.
+    // Once the client disconnects, writer_shutdown stops the manager and whatever tickets
+    // are still queued for this (dispatcher) client are handed to another dispatcher.
+    // Running out of time on shutdown stops the writer the same way.
+    let writer_shutdown = shutdown.abort.child_token();
+    let writer_cancelled = writer_shutdown.clone();
+    let shared_db_writer = shared_db.clone();
+
+    let manager = tokio::spawn(async move {
+        let mut result = Ok(());
+        // The message that was being written when writing failed.
+        let mut unwritten = None;
+
+        // Start receiving messages from the channel by calling the recv method of the Receiver endpoint.
+        // This method blocks until a message is received.
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = writer_cancelled.cancelled() => None,
+            };
+
+            let Some(msg) = msg else {
+                break;
+            };
+
+            // info!("Writer manager: sending {msg:?} to {addr}");
+            if let Err(e) = client_writer.send(msg.clone()).await {
+                unwritten = Some(msg);
+                result = Err(e);
+                break;
+            }
+            shared_db_writer.metrics().message_sent(&msg);
+
+            // An Error is always the last thing a client hears from us.
+            if let OutboundMessageType::Error(_) = msg {
+                result = client_writer.close().await;
+                break;
+            }
+        }
+
+        // Nothing will be written to this client anymore, re-queue its in-flight tickets.
+        // The channel is closed first: this client is still registered as a dispatcher and
+        // a ticket routed back here must fail right away instead of waiting for room.
+        rx.close();
+        let leftovers = unwritten
+            .into_iter()
+            .chain(iter::from_fn(|| rx.try_recv().ok()));
+        for msg in leftovers {
+            if let OutboundMessageType::Ticket { .. } = msg {
+                shared_db_writer.dispatch_ticket(msg).await;
+            }
+        }
+
+        result
+    });
+
+    // The heartbeat task of this connection, if the client asked for one.
+    let mut heartbeat = None;
+
+    // The first error ends the conversation with this client.
+    let mut outcome = Ok(());
+
+    // Connections are counted by what the client said it is.
+    let mut client_kind = ClientKind::Unidentified;
+
+    loop {
+        // Nothing more is read once the server shuts down or gives up on the client.
+        let message = tokio::select! {
+            message = client_reader.next() => message,
+            _ = shutdown.stop.cancelled() => None,
+            _ = dropped.cancelled() => Some(Err(SpeedDaemonError::SlowDispatcher)),
+        };
+        let Some(message) = message else {
+            break;
+        };
+
+        // info!("From {}: {:?}", addr, message);
+        match &message {
+            Ok(message) => shared_db.metrics().message_decoded(message),
+            Err(SpeedDaemonError::ParseFailure) => shared_db.metrics().parse_failure(),
+            Err(_) => {}
+        }
+
+        // Well-formed but nonsensical messages are rejected before they reach a handler.
+        let handled = match message.and_then(validate) {
+            Ok(InboundMessageType::Plate { plate, timestamp }) => {
+                handle_plate(&addr, plate, timestamp, &ticket_engine, shared_db.clone()).await
+            }
+
+            Ok(InboundMessageType::WantHeartbeat { interval }) => {
+                let tx_heartbeat = tx.clone();
+                handle_want_hearbeat(interval, &mut heartbeat, tx_heartbeat).await
+            }
+
+            // A client says what it is once, whether it was a camera or a dispatcher.
+            Ok(InboundMessageType::IAmCamera { .. } | InboundMessageType::IAmDispatcher { .. })
+                if client_kind != ClientKind::Unidentified =>
+            {
+                Err(SpeedDaemonError::AlreadyIdentified)
+            }
+
+            Ok(InboundMessageType::IAmCamera { road, mile, limit }) => {
+                let new_camera = InboundMessageType::IAmCamera { road, mile, limit };
+                let registered = handle_i_am_camera(&addr, new_camera, shared_db.clone()).await;
+                if registered.is_ok() {
+                    client_kind = ClientKind::Camera;
+                    shared_db.metrics().client_identified(client_kind);
+                }
+                registered
+            }
+
+            Ok(InboundMessageType::IAmDispatcher { roads }) => {
+                // info!("Dispatcher detected at address {}", addr);
+                let registered = handle_i_am_dispatcher(roads, &addr, &tx, shared_db.clone()).await;
+                if registered.is_ok() {
+                    client_kind = ClientKind::Dispatcher;
+                    shared_db.metrics().client_identified(client_kind);
+                }
+                registered
+            }
+
+            // Unknown or malformed message
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = handled {
+            outcome = Err(e);
+            break;
+        }
+    }
+
+    // The protocol requires the server to send an Error and then close the connection.
+    // The writer manager shuts the socket down right after the Error frame is written.
+    if let Err(e) = &outcome {
+        error!("{} from {:?}, disconnecting", e, addr);
+        // a failure means the writer manager has already stopped, the client is gone anyway
+        let _ = handle_error(e.to_string(), &tx).await;
+    }
+
+    // On shutdown a dispatcher stays registered until the last observations have been ticketed,
+    // then it writes out everything it was handed.
+    let shutting_down = shutdown.stop.is_cancelled();
+    if shutting_down && client_kind == ClientKind::Dispatcher && outcome.is_ok() {
+        shutdown.drained.cancelled().await;
+    }
+
+    // The client is gone: forget it, so no more tickets are routed here and it can reconnect.
+    shared_db.remove_camera(&addr).await;
+    shared_db.remove_ticket_dispatcher(&addr).await;
+    shared_db.metrics().connection_closed(client_kind);
+    shared_db.untrack_client(&addr);
+
+    if let Some(heartbeat) = heartbeat {
+        heartbeat.abort();
+    }
+
+    // Without an Error frame to flush, there's nothing left to write: stop the writer right away.
+    // Unless the server is shutting down, then the writer delivers what's queued first.
+    if outcome.is_ok() && !shutting_down {
+        writer_shutdown.cancel();
+    }
+    drop(tx);
+
+    if let Err(e) = manager.await.expect("Unable to await msg manager") {
+        error!("Error from the tx manager: {}", e)
+    }
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! A point-in-time copy of the shared state, as the admin endpoint shows it.
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use serde::Serialize;
+
+use crate::{
+    message::OutboundMessageType,
+    types::{Day, Limit, Mile, Plate, Road, Speed, Timestamp},
+};
+
+/// Everything the storage backend knows, in a form that serializes to readable JSON.
+///
+/// Shards are copied one after the other, so the snapshot may mix state from slightly
+/// different moments, e.g. a ticket both pending and already handed to a new dispatcher.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct StateSnapshot {
+    /// Connected cameras, ordered by road and mile.
+    pub cameras: Vec<CameraSnapshot>,
+    /// Connected dispatchers by road.
+    pub dispatchers: BTreeMap<Road, Vec<DispatcherSnapshot>>,
+    /// Tickets held in memory until a dispatcher for their road connects.
+    pub pending_tickets: Vec<TicketSnapshot>,
+    /// Pending tickets that only live on disk, by road. Only the SQLite backend spills tickets.
+    pub spilled_tickets: BTreeMap<Road, usize>,
+    /// What the ticket engine remembers of each road.
+    pub observations: BTreeMap<Road, ObservationCounts>,
+    /// The days every car has been ticketed for, ordered by road and plate.
+    pub issued_ticket_days: Vec<IssuedTicketDays>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct CameraSnapshot {
+    pub addr: SocketAddr,
+    pub road: Road,
+    pub mile: Mile,
+    pub limit: Limit,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DispatcherSnapshot {
+    pub addr: SocketAddr,
+    /// Messages waiting to be written to the dispatcher.
+    pub queued: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TicketSnapshot {
+    pub plate: Plate,
+    pub road: Road,
+    pub mile1: Mile,
+    pub timestamp1: Timestamp,
+    pub mile2: Mile,
+    pub timestamp2: Timestamp,
+    /// 100 times the average speed in mph, as sent to dispatchers.
+    pub speed: Speed,
+}
+
+impl TicketSnapshot {
+    /// `None` unless `message` is a ticket.
+    pub fn from_message(message: &OutboundMessageType) -> Option<TicketSnapshot> {
+        match message {
+            OutboundMessageType::Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            } => Some(TicketSnapshot {
+                plate: plate.clone(),
+                road: *road,
+                mile1: *mile1,
+                timestamp1: *timestamp1,
+                mile2: *mile2,
+                timestamp2: *timestamp2,
+                speed: *speed,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ObservationCounts {
+    /// Distinct plates seen on the road.
+    pub plates: usize,
+    pub observations: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct IssuedTicketDays {
+    pub plate: Plate,
+    pub road: Road,
+    /// In ascending order.
+    pub days: Vec<Day>,
+}
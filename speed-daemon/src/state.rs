@@ -1,356 +1,236 @@
-pub(crate) use std::collections::HashMap;
-use std::{net::SocketAddr, sync::Arc};
-
-use tokio::sync::Mutex;
+use std::{
+    collections::HashMap,
+    iter,
+    net::SocketAddr,
+    ops::Deref,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
-use log::{error, info, warn};
-use tokio::sync::mpsc;
+use log::{info, warn};
+use tokio::{
+    sync::mpsc::{
+        self,
+        error::{SendError, TrySendError},
+    },
+    task::JoinHandle,
+    time,
+};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     errors::SpeedDaemonError,
-    message::{InboundMessageType, OutboundMessageType},
-    types::{
-        CurrentCameraDb, IssuedTicketsDayDb, Mile, PlateRoadStruct, PlateRoadTimestampCameraDb,
-        Road, Speed, TicketDispatcherDb, TimestampCameraStruct,
-    },
+    message::OutboundMessageType,
+    metrics::Metrics,
+    policy::{RetentionPolicy, TicketPolicies, TicketPolicy},
+    queue::SlowDispatcherPolicy,
+    storage::{MemoryStorage, SqliteStorage, Storage},
+    types::Road,
 };
 
 // Reference: https://github.com/tokio-rs/mini-redis/blob/master/src/db.rs
 #[derive(Debug, Clone)]
 pub struct Db {
-    /// Handle to shared state. The background task will also have an
-    /// `Arc<Shared>`.
-    shared: Arc<Shared>,
-}
+    /// Handle to shared state. Every connection task holds a clone of the
+    /// `Arc`, the backend behind it decides where the state actually lives.
+    storage: Arc<dyn Storage>,
 
-#[derive(Debug)]
-struct Shared {
-    /// The shared state is guarded by a mutex. This is a `std::sync::Mutex` and
-    /// not a Tokio mutex. This is because there are no asynchronous operations
-    /// being performed while holding the mutex. Additionally, the critical
-    /// sections are very small.
-    ///
-    /// A Tokio mutex is mostly intended to be used when locks need to be held
-    /// across `.await` yield points. All other cases are **usually** best
-    /// served by a std mutex. If the critical section does not include any
-    /// async operations but is long (CPU intensive or performing blocking
-    /// operations), then the entire operation, including waiting for the mutex,
-    /// is considered a "blocking" operation and `tokio::task::spawn_blocking`
-    /// should be used.
-    state: Mutex<State>,
-}
+    /// Tolerance and rounding rules used when tickets are issued, fixed at startup.
+    policies: Arc<TicketPolicies>,
+
+    /// What to do with a dispatcher that doesn't keep up with its tickets.
+    slow_dispatcher: SlowDispatcherPolicy,
+
+    /// Which observations the sweeper evicts.
+    retention: RetentionPolicy,
 
-#[derive(Debug)]
-struct State {
-    dispatchers: TicketDispatcherDb,
-    current_camera: CurrentCameraDb,
-    plate_road_timestamp_camera: PlateRoadTimestampCameraDb,
-    issued_tickets_day: IssuedTicketsDayDb,
+    /// What the server has done so far, shared by every clone.
+    metrics: Arc<Metrics>,
+
+    /// Cancelled to make the server close a client's connection, by client address.
+    clients: Arc<Mutex<HashMap<SocketAddr, CancellationToken>>>,
 }
 
 impl Db {
+    /// Creates a `Db` that keeps everything in memory.
     pub fn new() -> Db {
-        let shared = Arc::new(Shared {
-            state: Mutex::new(State {
-                dispatchers: HashMap::new(),
-                current_camera: HashMap::new(),
-                // plates_tickets: HashMap::new(),
-                plate_road_timestamp_camera: HashMap::new(),
-                issued_tickets_day: HashMap::new(),
-            }),
-        });
-        Db { shared }
+        Db::with_storage(MemoryStorage::new())
     }
 
-    pub async fn add_plate_road_timestamp_camera(
-        &self,
-        plate_road: PlateRoadStruct,
-        ts_camera: TimestampCameraStruct,
-    ) {
-        let mut state = self.shared.state.lock().await;
-
-        // This keeps the array sorted. Should be ok for smaller arrays otherwise BinaryHeap is probably better.
-        match state
-            .plate_road_timestamp_camera
-            .entry(plate_road.clone())
-            .or_default()
-            .binary_search(&ts_camera)
-        {
-            Ok(_) => {}
-            Err(position) => {
-                state
-                    .plate_road_timestamp_camera
-                    .entry(plate_road.clone())
-                    .or_default()
-                    .insert(position, ts_camera);
-            }
+    /// Creates a `Db` on top of any [`Storage`] backend.
+    pub fn with_storage<S: Storage + 'static>(storage: S) -> Db {
+        Db {
+            storage: Arc::new(storage),
+            policies: Arc::new(TicketPolicies::default()),
+            slow_dispatcher: SlowDispatcherPolicy::default(),
+            retention: RetentionPolicy::default(),
+            metrics: Arc::new(Metrics::new()),
+            clients: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    // This will return a Vec of tickets in a given road where the average speed exceeded the limit between
-    // any pair of observations on the same road, even if the observations were not from adjacent cameras.
-    pub async fn get_ticket_for_plate(
-        &self,
-        plate_road: &PlateRoadStruct,
-    ) -> Option<Vec<OutboundMessageType>> {
-        async fn calculate_average_speed(
-            observation1: &TimestampCameraStruct,
-            observation2: &TimestampCameraStruct,
-        ) -> Result<u32, SpeedDaemonError> {
-            let mut mile1: Mile = 0;
-            let mut mile2: Mile = 0;
-
-            if let InboundMessageType::IAmCamera {
-                road: _,
-                mile,
-                limit: _,
-            } = observation1.camera
-            {
-                mile1 = mile;
-            };
-
-            if let InboundMessageType::IAmCamera {
-                road: _,
-                mile,
-                limit: _,
-            } = observation2.camera
-            {
-                mile2 = mile;
-            };
-
-            // need to x3600 to convert mi/sec to mi/hr. Later, we'll x100 the actual ticket to comply with the spec.
-            let distance_traveled = mile1.abs_diff(mile2) as u32;
-            let time_traveled = observation1.timestamp.abs_diff(observation2.timestamp);
-
-            let average_speed =
-                ((distance_traveled as f32 * 3600.0) / time_traveled as f32).round() as u32;
-
-            // info!(
-            //     "For {:?} avg speed between {:?} and {:?} was {}",
-            //     plate_road, observation1, observation2, average_speed
-            // );
-
-            Ok(average_speed)
+    /// Replaces the default ticket policies.
+    pub fn with_policies(self, policies: TicketPolicies) -> Db {
+        Db {
+            policies: Arc::new(policies),
+            ..self
         }
+    }
 
-        let mut state = self.shared.state.lock().await;
-        let mut tickets: Vec<OutboundMessageType> = Vec::new();
-
-        if let Some(vec_of_ts_cameras) = state.plate_road_timestamp_camera.clone().get(plate_road) {
-            if vec_of_ts_cameras.len() < 2 {
-                // warn!(
-                //     "{:?} has fewer than 2 elements in {:?}, no ticket.",
-                //     plate_road, vec_of_ts_cameras
-                // );
-                return None;
-            }
-
-            // info!(
-            //     "{:?} has more than 2 elements in {:?}, proceeding.",
-            //     plate_road, vec_of_ts_cameras
-            // );
-
-            // Master checker loop, looking at consecutive pairs of observations
-            // for pair in vec_of_ts_cameras.chunks(2) {
-            for (i, _) in vec_of_ts_cameras.iter().enumerate() {
-                let first: &TimestampCameraStruct;
-                let second: &TimestampCameraStruct;
-
-                // index starts at 0 but the length is one more
-                if i < (vec_of_ts_cameras.len() - 1) {
-                    first = &vec_of_ts_cameras[i];
-                    second = &vec_of_ts_cameras[i + 1];
-                } else {
-                    break; // last index, bailing
-                }
-
-                // info!(
-                //     "For {:?} comparing {:?} with {:?}",
-                //     plate_road, first, second
-                // );
-
-                // Get the speed limit, it will be common across both observations since there's only 1 limit per road
-                let mut common_limit = 0;
-
-                if let InboundMessageType::IAmCamera {
-                    road: _,
-                    mile: _,
-                    limit,
-                } = first.camera
-                {
-                    common_limit = limit;
-                };
+    /// Replaces the default policy for dispatchers whose queue is full.
+    pub fn with_slow_dispatcher(self, slow_dispatcher: SlowDispatcherPolicy) -> Db {
+        Db {
+            slow_dispatcher,
+            ..self
+        }
+    }
 
-                // Then, let's calculate the average speed between two observations
-                let average_speed = calculate_average_speed(first, second)
-                    .await
-                    .expect("Failed to get average speed");
+    /// Replaces the default retention policy, which keeps every observation.
+    pub fn with_retention(self, retention: RetentionPolicy) -> Db {
+        Db { retention, ..self }
+    }
 
-                // Calculate the days for both observations
-                let day1 = (first.timestamp as f32 / 86400.0).floor() as u32;
-                let day2 = (second.timestamp as f32 / 86400.0).floor() as u32;
+    /// Spawns the background task that evicts observations according to the retention policy,
+    /// unless the policy keeps everything.
+    pub fn spawn_sweeper(&self) -> Option<JoinHandle<()>> {
+        if self.retention.keeps_everything() {
+            return None;
+        }
 
-                // info!(
-                //     "For {:?} timestamp1: {} timestamp2: {} day1: {} day2: {} avg speed: {}",
-                //     plate_road, first.timestamp, second.timestamp, day1, day2, average_speed
-                // );
+        let shared_db = self.clone();
+        Some(tokio::spawn(async move {
+            let mut ticker = time::interval(shared_db.retention.sweep_interval);
+            loop {
+                ticker.tick().await;
 
-                // Assume we are going to create a ticket unless it was already issued this day
-                let mut issue_ticket = true;
-                if let Some(days) = state.issued_tickets_day.get(plate_road) {
-                    // check the current second day against every day we've issued tickets before
-                    for day in days.iter() {
-                        if *day == day1 || *day == day2 {
-                            // warn!(
-                            //     "{:?} was previously issued tickets on day {:?}, no ticket.",
-                            //     plate_road, day
-                            // );
-                            // there will be no ticket issued
-                            issue_ticket = false;
-                        }
-                    }
+                let evicted = shared_db.evict_observations(&shared_db.retention).await;
+                if evicted > 0 {
+                    info!("Evicted {} observations", evicted);
                 }
+            }
+        }))
+    }
 
-                if average_speed > common_limit.into() && issue_ticket {
-                    let mut mile1: Mile = 0;
-                    let mut mile2: Mile = 0;
-
-                    // info!(
-                    //     "For {:?} between {:?} and {:?} average speed was {}",
-                    //     plate_road, first, second, average_speed
-                    // );
-
-                    if let InboundMessageType::IAmCamera {
-                        road: _,
-                        mile,
-                        limit: _,
-                    } = first.camera
-                    {
-                        mile1 = mile;
-                    };
-
-                    if let InboundMessageType::IAmCamera {
-                        road: _,
-                        mile,
-                        limit: _,
-                    } = second.camera
-                    {
-                        mile2 = mile;
-                    };
-
-                    // mile1 and timestamp1 must refer to the earlier of the 2 observations (the smaller timestamp),
-                    // and mile2 and timestamp2 must refer to the later of the 2 observations (the larger timestamp).
-                    let timestamp1 = first.timestamp;
-                    let timestamp2 = second.timestamp;
-
-                    // mile1 and timestamp1 must refer to the earlier of the 2 observations (the smaller timestamp),
-                    // and mile2 and timestamp2 must refer to the later of the 2 observations (the larger timestamp).
-                    if timestamp1 > timestamp2 {
-                        // observation 1 > observation 2, need to swap mile1 & mile2
-                        (mile1, mile2) = (mile2, mile1);
-                    }
-
-                    let new_ticket = OutboundMessageType::Ticket {
-                        plate: plate_road.plate.clone(),
-                        road: plate_road.road,
-                        mile1,
-                        timestamp1: timestamp1.min(timestamp2),
-                        mile2,
-                        timestamp2: timestamp1.max(timestamp2),
-                        speed: (average_speed * 100) as Speed,
-                    };
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
 
-                    state
-                        .issued_tickets_day
-                        .entry(plate_road.clone())
-                        .or_default()
-                        .insert(day1);
+    /// Registers the connection of the client at `addr`. The returned token is cancelled
+    /// once the server wants the connection closed, e.g. a dispatcher that can't keep up.
+    pub fn track_client(&self, addr: SocketAddr) -> CancellationToken {
+        let dropped = CancellationToken::new();
+        self.clients
+            .lock()
+            .expect("clients lock poisoned")
+            .insert(addr, dropped.clone());
+        dropped
+    }
 
-                    state
-                        .issued_tickets_day
-                        .entry(plate_road.clone())
-                        .or_default()
-                        .insert(day2);
+    /// Forgets the connection of the client at `addr` once it has ended.
+    pub fn untrack_client(&self, addr: &SocketAddr) {
+        self.clients
+            .lock()
+            .expect("clients lock poisoned")
+            .remove(addr);
+    }
 
-                    // info!(
-                    //     "{:?} ready, stored day1: {} day2: {}, dispatching.",
-                    //     new_ticket, day1, day2
-                    // );
-                    tickets.push(new_ticket);
-                } else {
-                    // info!(
-                    //     "For {:?} avg speed was {} but limit was {}, no ticket",
-                    //     plate_road, average_speed, common_limit
-                    // );
-                }
-            }
+    /// Asks the connection of the client at `addr` to end.
+    fn drop_client(&self, addr: &SocketAddr) {
+        if let Some(dropped) = self
+            .clients
+            .lock()
+            .expect("clients lock poisoned")
+            .get(addr)
+        {
+            dropped.cancel();
         }
-
-        Some(tickets)
     }
 
-    // This is invoked by handle_i_am_camera when a new camera comes online.
-    pub async fn add_camera(&mut self, addr: SocketAddr, new_camera: InboundMessageType) {
-        let mut state = self.shared.state.lock().await;
-
-        state.current_camera.insert(addr, new_camera);
+    /// The policy tickets on `road` are issued under.
+    pub fn ticket_policy(&self, road: Road) -> TicketPolicy {
+        self.policies.for_road(road)
     }
 
-    pub async fn get_current_camera(&self, addr: &SocketAddr) -> Option<InboundMessageType> {
-        let state = self.shared.state.lock().await;
+    /// Creates a `Db` backed by the SQLite database at `path`, reloading everything a previous
+    /// run has persisted there into `shards` shards. At most `pending_limit` undelivered tickets
+    /// are kept in memory.
+    pub async fn with_sqlite<P: AsRef<Path>>(
+        path: P,
+        shards: usize,
+        pending_limit: usize,
+    ) -> Result<Db, SpeedDaemonError> {
+        Ok(Db::with_storage(
+            SqliteStorage::open(path, shards, pending_limit).await?,
+        ))
+    }
 
-        state.current_camera.get(addr).cloned()
+    /// Routes a ticket the ticket engine has just issued, see [`Db::dispatch_ticket`].
+    pub async fn issue_ticket(&self, ticket: OutboundMessageType) {
+        self.metrics.ticket_issued(&ticket);
+        self.dispatch_ticket(ticket).await
     }
 
-    pub async fn ticket_dispatcher_already_exists(&self, addr: &SocketAddr) -> bool {
-        let state = self.shared.state.lock().await;
+    /// Sends the ticket to one of the dispatchers for its road. If that dispatcher has gone away,
+    /// or can't keep up and the policy is to drop it, it is deregistered and the next one is tried;
+    /// with none left the ticket stays pending. A dropped dispatcher is disconnected as well, so it
+    /// can reconnect and pick up the tickets that piled up meanwhile.
+    pub async fn dispatch_ticket(&self, mut ticket: OutboundMessageType) {
+        while let Some((addr, tx)) = self.dispatch_or_queue_ticket(&ticket).await {
+            let undelivered = match self.slow_dispatcher {
+                SlowDispatcherPolicy::Block => match tx.send(ticket).await {
+                    Ok(()) => return,
+                    Err(SendError(undelivered)) => {
+                        warn!("Dispatcher {addr} is gone, trying another one");
+                        undelivered
+                    }
+                },
+                SlowDispatcherPolicy::Drop => match tx.try_send(ticket) {
+                    Ok(()) => return,
+                    Err(TrySendError::Full(undelivered)) => {
+                        warn!("Dispatcher {addr} can't keep up, disconnecting it");
+                        self.drop_client(&addr);
+                        undelivered
+                    }
+                    Err(TrySendError::Closed(undelivered)) => {
+                        warn!("Dispatcher {addr} is gone, trying another one");
+                        undelivered
+                    }
+                },
+            };
 
-        for (_key, addr_to_tx_hashmap) in state.dispatchers.iter() {
-            if addr_to_tx_hashmap.contains_key(addr) {
-                return true;
-            }
+            self.remove_ticket_dispatcher(&addr).await;
+            ticket = undelivered;
         }
-
-        false
     }
 
-    pub async fn add_ticket_dispatcher(
-        &mut self,
-        road: Road,
-        addr: SocketAddr,
-        tx: mpsc::Sender<OutboundMessageType>,
-    ) {
-        let mut addr_tx_hash = HashMap::new();
-        addr_tx_hash.insert(addr, tx);
-        let mut state = self.shared.state.lock().await;
+    /// Hands the tickets that were pending for a road to the dispatcher that just registered
+    /// for it, in order. They're no longer in storage, so should the dispatcher go away halfway
+    /// the rest are dispatched again, to another dispatcher or back to pending.
+    pub async fn deliver_pending_tickets(
+        &self,
+        tickets: Vec<OutboundMessageType>,
+        tx: &mpsc::Sender<OutboundMessageType>,
+    ) -> Result<(), SpeedDaemonError> {
+        let mut tickets = tickets.into_iter();
+
+        while let Some(ticket) = tickets.next() {
+            if let Err(SendError(undelivered)) = tx.send(ticket).await {
+                for ticket in iter::once(undelivered).chain(tickets) {
+                    self.dispatch_ticket(ticket).await;
+                }
+                return Err(SpeedDaemonError::DisconnectedClient);
+            }
+        }
 
-        state.dispatchers.insert(road, addr_tx_hash);
+        Ok(())
     }
+}
 
-    pub async fn get_ticket_dispatcher(
-        &self,
-        road: &Road,
-    ) -> Option<mpsc::Sender<OutboundMessageType>> {
-        let state = self.shared.state.lock().await;
+impl Deref for Db {
+    type Target = dyn Storage;
 
-        // First, we get the hash mapping the road num to the client address-tx hash
-        // Second, we get the tx from the client address.
-        // NOTE: this overrides the previous ticket dispatcher for the same road. PROBLEM?
-        if let Some(addr_tx_hash) = state.dispatchers.get(road) {
-            if let Some((_client_addr, tx)) = addr_tx_hash.iter().next() {
-                // info!("Found a dispatcher for road {} at {}", road, client_addr);
-                Some(tx.clone())
-            } else {
-                error!(
-                    "BIG PROBLEM, dispatcher was added but somehow not found for road {}!",
-                    road
-                );
-                None
-            }
-        } else {
-            // warn!("No dispatcher found for road {} try again later", road);
-            None
-        }
+    fn deref(&self) -> &Self::Target {
+        self.storage.as_ref()
     }
 }
 
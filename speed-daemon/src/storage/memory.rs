@@ -0,0 +1,505 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex, RwLock};
+
+use crate::{
+    errors::SpeedDaemonError,
+    message::{InboundMessageType, OutboundMessageType},
+    policy::{RetentionPolicy, TicketPolicy},
+    queue::QueueDepths,
+    snapshot::{
+        CameraSnapshot, DispatcherSnapshot, IssuedTicketDays, StateSnapshot, TicketSnapshot,
+    },
+    ticket::check_new_observation,
+    types::{
+        CurrentCameraDb, Day, IssuedTicketsDayDb, PendingTicketDb, PlateRoadStruct,
+        PlateRoadTimestampCameraDb, Road, TicketDispatcherDb, Timestamp, TimestampCameraStruct,
+    },
+};
+
+use super::Storage;
+
+/// Number of shards the road state is split into unless asked otherwise.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// The default backend: everything lives in `HashMap`s and is lost when the process exits.
+///
+/// Everything that belongs to a road (its observations, ticketed days, dispatchers and pending
+/// tickets) lives in one of several shards, picked by road number, each behind its own lock.
+/// Cameras and dispatchers on different roads therefore rarely wait for each other, while
+/// everything about a single road can still be changed atomically.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    /// Each shard is guarded by a Tokio mutex, so a task waiting for a busy shard yields
+    /// its runtime thread instead of blocking it. Nothing is awaited while a shard is held
+    /// and the critical sections are short, a shard is only contended when many tasks
+    /// need roads in it at the same time.
+    shards: Box<[Mutex<Shard>]>,
+
+    /// Cameras by connection. Every plate reads it, only new and closing connections write it.
+    current_camera: RwLock<CurrentCameraDb>,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    dispatchers: TicketDispatcherDb,
+    plate_road_timestamp_camera: PlateRoadTimestampCameraDb,
+    issued_tickets_day: IssuedTicketsDayDb,
+    pending_tickets: PendingTicketDb,
+    /// The newest timestamp seen on each road, the retention policy's notion of "now" there.
+    /// Per road, so a camera with a clock far ahead can't age out everybody else's state.
+    newest: HashMap<Road, Timestamp>,
+}
+
+/// What [`MemoryStorage::evict`] forgot.
+#[derive(Debug, Default)]
+pub(crate) struct Evicted {
+    pub observations: Vec<(PlateRoadStruct, Timestamp)>,
+    /// The oldest ticketed day kept on each road, the ones before it are gone.
+    pub oldest_days: Vec<(Road, Day)>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Creates an empty store split into `shards` shards (at least one).
+    pub fn with_shards(shards: usize) -> MemoryStorage {
+        MemoryStorage::with_history(shards, HashMap::new(), HashMap::new(), Vec::new())
+    }
+
+    /// Creates a store pre-populated with observations, issued ticket days and undelivered
+    /// tickets, e.g. the ones a durable backend recovered at startup.
+    pub fn with_history(
+        shards: usize,
+        plate_road_timestamp_camera: PlateRoadTimestampCameraDb,
+        issued_tickets_day: IssuedTicketsDayDb,
+        pending: Vec<OutboundMessageType>,
+    ) -> MemoryStorage {
+        let mut shards: Vec<Shard> = (0..shards.max(1)).map(|_| Shard::default()).collect();
+        let count = shards.len();
+
+        for (plate_road, observations) in plate_road_timestamp_camera {
+            let shard = &mut shards[shard_index(plate_road.road, count)];
+
+            if let Some(last) = observations.last() {
+                let newest = shard.newest.entry(plate_road.road).or_default();
+                *newest = (*newest).max(last.timestamp);
+            }
+            shard
+                .plate_road_timestamp_camera
+                .insert(plate_road, observations);
+        }
+        for (plate_road, days) in issued_tickets_day {
+            shards[shard_index(plate_road.road, count)]
+                .issued_tickets_day
+                .insert(plate_road, days);
+        }
+        for ticket in pending {
+            if let OutboundMessageType::Ticket { road, .. } = ticket {
+                shards[shard_index(road, count)]
+                    .pending_tickets
+                    .entry(road)
+                    .or_default()
+                    .push(ticket);
+            }
+        }
+
+        MemoryStorage {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            current_camera: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Number of shards the road state is split into.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard(&self, road: Road) -> &Mutex<Shard> {
+        &self.shards[shard_index(road, self.shards.len())]
+    }
+
+    /// Number of tickets waiting for a dispatcher, on every road.
+    pub(crate) async fn pending_ticket_count(&self) -> usize {
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            let shard = shard.lock().await;
+
+            count += shard.pending_tickets.values().map(Vec::len).sum::<usize>();
+        }
+
+        count
+    }
+
+    /// Forgets every observation and ticketed day `policy` doesn't keep, each road measured
+    /// against its own newest timestamp, and returns what's gone.
+    pub(crate) async fn evict(&self, policy: &RetentionPolicy) -> Evicted {
+        let mut evicted = Evicted::default();
+
+        // One shard at a time, cameras on the other roads carry on meanwhile.
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().await;
+            let Shard {
+                plate_road_timestamp_camera,
+                issued_tickets_day,
+                newest,
+                ..
+            } = &mut *shard;
+
+            plate_road_timestamp_camera.retain(|plate_road, observations| {
+                let issued_days = issued_tickets_day.get(plate_road);
+                let newest = newest.get(&plate_road.road).copied().unwrap_or_default();
+                evicted.observations.extend(
+                    policy
+                        .evict(observations, newest, issued_days)
+                        .into_iter()
+                        .map(|timestamp| (plate_road.clone(), timestamp)),
+                );
+
+                !observations.is_empty()
+            });
+
+            // A road nothing was seen on since the start keeps its ticketed days.
+            issued_tickets_day.retain(|plate_road, days| {
+                if let Some(newest) = newest.get(&plate_road.road) {
+                    policy.evict_days(days, *newest);
+                }
+                !days.is_empty()
+            });
+
+            evicted.oldest_days.extend(
+                newest
+                    .iter()
+                    .filter_map(|(road, newest)| Some((*road, policy.oldest_kept_day(*newest)?))),
+            );
+        }
+
+        evicted
+    }
+
+    /// Drops a pending ticket from memory, e.g. once it's safely stored somewhere else.
+    pub(crate) async fn forget_pending_ticket(&self, ticket: &OutboundMessageType) {
+        let OutboundMessageType::Ticket { road, .. } = ticket else {
+            return;
+        };
+
+        let mut shard = self.shard(*road).lock().await;
+
+        if let Some(pending) = shard.pending_tickets.get_mut(road) {
+            pending.retain(|pending_ticket| pending_ticket != ticket);
+            if pending.is_empty() {
+                shard.pending_tickets.remove(road);
+            }
+        }
+    }
+}
+
+/// The shard a road lives in. The ticket engine partitions its workers the same way.
+pub fn shard_index(road: Road, shards: usize) -> usize {
+    road as usize % shards
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn add_plate_road_timestamp_camera(
+        &self,
+        plate_road: PlateRoadStruct,
+        ts_camera: TimestampCameraStruct,
+    ) -> Result<(), SpeedDaemonError> {
+        let mut shard = self.shard(plate_road.road).lock().await;
+
+        let newest = shard.newest.entry(plate_road.road).or_default();
+        *newest = (*newest).max(ts_camera.timestamp);
+
+        // This keeps the array sorted. Should be ok for smaller arrays otherwise BinaryHeap is probably better.
+        let observations = shard
+            .plate_road_timestamp_camera
+            .entry(plate_road.clone())
+            .or_default();
+        match observations.binary_search(&ts_camera) {
+            // the same camera reporting the same sighting twice
+            Ok(position) if observations[position].camera == ts_camera.camera => {
+                Err(SpeedDaemonError::DuplicateObservation {
+                    plate: plate_road.plate,
+                    timestamp: ts_camera.timestamp,
+                })
+            }
+            // another camera at the very same time, only the first sighting is kept
+            Ok(_) => Ok(()),
+            Err(position) => {
+                observations.insert(position, ts_camera);
+                Ok(())
+            }
+        }
+    }
+
+    async fn evict_observations(&self, policy: &RetentionPolicy) -> usize {
+        self.evict(policy).await.observations.len()
+    }
+
+    async fn get_ticket_for_plate(
+        &self,
+        plate_road: &PlateRoadStruct,
+        new_observation: &TimestampCameraStruct,
+        policy: &TicketPolicy,
+    ) -> Option<Vec<OutboundMessageType>> {
+        let mut shard = self.shard(plate_road.road).lock().await;
+        let Shard {
+            plate_road_timestamp_camera,
+            issued_tickets_day,
+            ..
+        } = &mut *shard;
+
+        let observations = plate_road_timestamp_camera.get(plate_road)?;
+
+        // Most cars are never ticketed, they only get an entry once they are.
+        let tickets = match issued_tickets_day.get_mut(plate_road) {
+            Some(issued_days) => check_new_observation(
+                plate_road,
+                observations,
+                new_observation,
+                policy,
+                issued_days,
+            ),
+            None => {
+                let mut issued_days = HashSet::new();
+                let tickets = check_new_observation(
+                    plate_road,
+                    observations,
+                    new_observation,
+                    policy,
+                    &mut issued_days,
+                );
+                if !issued_days.is_empty() {
+                    issued_tickets_day.insert(plate_road.clone(), issued_days);
+                }
+                tickets
+            }
+        };
+
+        Some(tickets)
+    }
+
+    async fn add_camera(&self, addr: SocketAddr, new_camera: InboundMessageType) {
+        let mut current_camera = self.current_camera.write().await;
+
+        current_camera.insert(addr, new_camera);
+    }
+
+    async fn get_current_camera(&self, addr: &SocketAddr) -> Option<InboundMessageType> {
+        let current_camera = self.current_camera.read().await;
+
+        current_camera.get(addr).cloned()
+    }
+
+    async fn remove_camera(&self, addr: &SocketAddr) {
+        let mut current_camera = self.current_camera.write().await;
+
+        current_camera.remove(addr);
+    }
+
+    async fn ticket_dispatcher_already_exists(&self, addr: &SocketAddr) -> bool {
+        for shard in self.shards.iter() {
+            let shard = shard.lock().await;
+
+            if shard
+                .dispatchers
+                .values()
+                .any(|road_dispatchers| road_dispatchers.contains(addr))
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    async fn add_ticket_dispatcher(
+        &self,
+        road: Road,
+        addr: SocketAddr,
+        tx: mpsc::Sender<OutboundMessageType>,
+    ) -> Vec<OutboundMessageType> {
+        let mut shard = self.shard(road).lock().await;
+
+        shard.dispatchers.entry(road).or_default().add(addr, tx);
+
+        // Registration and the flush happen under the same lock, so a ticket can't be
+        // queued for this road after we've drained it.
+        shard.pending_tickets.remove(&road).unwrap_or_default()
+    }
+
+    async fn remove_ticket_dispatcher(&self, addr: &SocketAddr) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().await;
+
+            for road_dispatchers in shard.dispatchers.values_mut() {
+                road_dispatchers.remove(addr);
+            }
+            shard
+                .dispatchers
+                .retain(|_road, road_dispatchers| !road_dispatchers.is_empty());
+        }
+    }
+
+    async fn get_ticket_dispatcher(
+        &self,
+        road: &Road,
+    ) -> Option<mpsc::Sender<OutboundMessageType>> {
+        let mut shard = self.shard(*road).lock().await;
+
+        shard
+            .dispatchers
+            .get_mut(road)
+            .and_then(|road_dispatchers| road_dispatchers.next_dispatcher())
+            .map(|(_client_addr, tx)| tx)
+    }
+
+    async fn dispatch_or_queue_ticket(
+        &self,
+        ticket: &OutboundMessageType,
+    ) -> Option<(SocketAddr, mpsc::Sender<OutboundMessageType>)> {
+        let OutboundMessageType::Ticket { road, .. } = ticket else {
+            return None;
+        };
+
+        let mut shard = self.shard(*road).lock().await;
+
+        if let Some(dispatcher) = shard
+            .dispatchers
+            .get_mut(road)
+            .and_then(|road_dispatchers| road_dispatchers.next_dispatcher())
+        {
+            return Some(dispatcher);
+        }
+
+        // warn!("No dispatcher found for road {}, ticket is pending", road);
+        shard
+            .pending_tickets
+            .entry(*road)
+            .or_default()
+            .push(ticket.clone());
+        None
+    }
+
+    async fn pending_tickets(&self) -> Vec<OutboundMessageType> {
+        let mut pending_tickets = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().await;
+
+            pending_tickets.extend(shard.pending_tickets.values().flatten().cloned());
+        }
+
+        pending_tickets
+    }
+
+    async fn pending_tickets_by_road(&self) -> HashMap<Road, usize> {
+        let mut counts = HashMap::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().await;
+
+            counts.extend(
+                shard
+                    .pending_tickets
+                    .iter()
+                    .map(|(road, pending)| (*road, pending.len())),
+            );
+        }
+
+        counts
+    }
+
+    async fn queue_depths(&self) -> QueueDepths {
+        let mut depths = QueueDepths::default();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().await;
+
+            depths.dispatchers.extend(
+                shard
+                    .dispatchers
+                    .values()
+                    .flat_map(|road_dispatchers| road_dispatchers.queue_depths()),
+            );
+            depths.pending_tickets += shard.pending_tickets.values().map(Vec::len).sum::<usize>();
+        }
+
+        depths
+    }
+
+    async fn snapshot(&self) -> StateSnapshot {
+        let mut snapshot = StateSnapshot::default();
+
+        for (addr, camera) in self.current_camera.read().await.iter() {
+            if let InboundMessageType::IAmCamera { road, mile, limit } = camera {
+                snapshot.cameras.push(CameraSnapshot {
+                    addr: *addr,
+                    road: *road,
+                    mile: *mile,
+                    limit: *limit,
+                });
+            }
+        }
+        snapshot
+            .cameras
+            .sort_by_key(|camera| (camera.road, camera.mile, camera.addr));
+
+        for shard in self.shards.iter() {
+            let shard = shard.lock().await;
+
+            for (road, road_dispatchers) in shard.dispatchers.iter() {
+                snapshot.dispatchers.insert(
+                    *road,
+                    road_dispatchers
+                        .queue_depths()
+                        .map(|(addr, queued)| DispatcherSnapshot { addr, queued })
+                        .collect(),
+                );
+            }
+
+            snapshot.pending_tickets.extend(
+                shard
+                    .pending_tickets
+                    .values()
+                    .flatten()
+                    .filter_map(TicketSnapshot::from_message),
+            );
+
+            for (plate_road, observations) in shard.plate_road_timestamp_camera.iter() {
+                let counts = snapshot.observations.entry(plate_road.road).or_default();
+                counts.plates += 1;
+                counts.observations += observations.len();
+            }
+
+            for (plate_road, days) in shard.issued_tickets_day.iter() {
+                let mut days: Vec<_> = days.iter().copied().collect();
+                days.sort_unstable();
+                snapshot.issued_ticket_days.push(IssuedTicketDays {
+                    plate: plate_road.plate.clone(),
+                    road: plate_road.road,
+                    days,
+                });
+            }
+        }
+
+        snapshot
+            .pending_tickets
+            .sort_by_key(|ticket| (ticket.road, ticket.timestamp1));
+        snapshot
+            .issued_ticket_days
+            .sort_by(|a, b| (a.road, &a.plate).cmp(&(b.road, &b.plate)));
+
+        snapshot
+    }
+}
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, fmt::Debug, net::SocketAddr};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::{
+    errors::SpeedDaemonError,
+    message::{InboundMessageType, OutboundMessageType},
+    policy::{RetentionPolicy, TicketPolicy},
+    queue::QueueDepths,
+    snapshot::StateSnapshot,
+    types::{PlateRoadStruct, Road, TimestampCameraStruct},
+};
+
+mod memory;
+mod sqlite;
+
+pub use memory::{shard_index, MemoryStorage, DEFAULT_SHARDS};
+pub use sqlite::{PersistedState, SqliteStorage, SqliteStore};
+
+/// Everything the connection handlers need from the shared state.
+///
+/// `state::Db` hands out an `Arc<dyn Storage>`, so a backend can be swapped
+/// (in-memory, persistent, a mock in tests) without touching the connection logic.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Records a plate sighting. Observations are kept sorted by timestamp.
+    /// Fails with `DuplicateObservation` if the same camera already reported the plate at that time.
+    /// A sighting at the same time by another camera is ignored, only the first one is kept.
+    async fn add_plate_road_timestamp_camera(
+        &self,
+        plate_road: PlateRoadStruct,
+        ts_camera: TimestampCameraStruct,
+    ) -> Result<(), SpeedDaemonError>;
+
+    /// Forgets every observation `policy` doesn't keep and returns how many were evicted.
+    async fn evict_observations(&self, policy: &RetentionPolicy) -> usize;
+
+    /// Returns the tickets `policy` says `new_observation` (already added for `plate_road`) warrants,
+    /// recording every day they cover so a car is ticketed at most once per day.
+    async fn get_ticket_for_plate(
+        &self,
+        plate_road: &PlateRoadStruct,
+        new_observation: &TimestampCameraStruct,
+        policy: &TicketPolicy,
+    ) -> Option<Vec<OutboundMessageType>>;
+
+    /// This is invoked by handle_i_am_camera when a new camera comes online.
+    async fn add_camera(&self, addr: SocketAddr, new_camera: InboundMessageType);
+
+    async fn get_current_camera(&self, addr: &SocketAddr) -> Option<InboundMessageType>;
+
+    /// Forgets the camera at `addr` once its connection ends.
+    async fn remove_camera(&self, addr: &SocketAddr);
+
+    async fn ticket_dispatcher_already_exists(&self, addr: &SocketAddr) -> bool;
+
+    /// Registers `tx` as a dispatcher for `road` and returns the tickets that were
+    /// waiting for that road, which the caller must now deliver to `tx`.
+    async fn add_ticket_dispatcher(
+        &self,
+        road: Road,
+        addr: SocketAddr,
+        tx: mpsc::Sender<OutboundMessageType>,
+    ) -> Vec<OutboundMessageType>;
+
+    /// Forgets the dispatcher at `addr` on every road, e.g. once its channel turned out to be closed.
+    async fn remove_ticket_dispatcher(&self, addr: &SocketAddr);
+
+    /// Returns the next dispatcher for `road`, rotating through all of them.
+    async fn get_ticket_dispatcher(&self, road: &Road)
+        -> Option<mpsc::Sender<OutboundMessageType>>;
+
+    /// Returns the dispatcher the ticket should be sent to or, if no dispatcher is
+    /// connected for its road, keeps the ticket pending until one registers.
+    async fn dispatch_or_queue_ticket(
+        &self,
+        ticket: &OutboundMessageType,
+    ) -> Option<(SocketAddr, mpsc::Sender<OutboundMessageType>)>;
+
+    /// Returns every ticket still waiting for a dispatcher and held in memory.
+    async fn pending_tickets(&self) -> Vec<OutboundMessageType>;
+
+    /// Number of tickets waiting for a dispatcher on each road that has any, held in memory or not.
+    async fn pending_tickets_by_road(&self) -> HashMap<Road, usize>;
+
+    /// How many messages wait in the dispatcher queues and the pending tickets.
+    /// The ticket engine's own queue isn't known here and is left at 0.
+    async fn queue_depths(&self) -> QueueDepths;
+
+    /// Copies the whole state for inspection. Holds each lock only long enough to copy what's behind it.
+    async fn snapshot(&self) -> StateSnapshot;
+}
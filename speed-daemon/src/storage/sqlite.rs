@@ -0,0 +1,623 @@
+use std::{collections::HashMap, net::SocketAddr, path::Path};
+
+use async_trait::async_trait;
+use log::error;
+use rusqlite::{params, Row};
+use tokio::sync::{mpsc, oneshot, Mutex, MutexGuard};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    errors::SpeedDaemonError,
+    message::{InboundMessageType, OutboundMessageType},
+    policy::{RetentionPolicy, TicketPolicy},
+    queue::QueueDepths,
+    snapshot::StateSnapshot,
+    ticket::ticket_days,
+    types::{
+        Day, IssuedTicketsDayDb, Limit, Mile, Plate, PlateRoadStruct, PlateRoadTimestampCameraDb,
+        Road, Timestamp, TimestampCameraStruct,
+    },
+};
+
+use super::{shard_index, MemoryStorage, Storage};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS observations (
+        plate     TEXT    NOT NULL,
+        road      INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        mile      INTEGER NOT NULL,
+        speed_limit INTEGER NOT NULL,
+        PRIMARY KEY (plate, road, timestamp)
+    );
+
+    CREATE TABLE IF NOT EXISTS issued_ticket_days (
+        plate TEXT    NOT NULL,
+        road  INTEGER NOT NULL,
+        day   INTEGER NOT NULL,
+        PRIMARY KEY (plate, road, day)
+    );
+
+    CREATE TABLE IF NOT EXISTS pending_tickets (
+        plate      TEXT    NOT NULL,
+        road       INTEGER NOT NULL,
+        mile1      INTEGER NOT NULL,
+        timestamp1 INTEGER NOT NULL,
+        mile2      INTEGER NOT NULL,
+        timestamp2 INTEGER NOT NULL,
+        speed      INTEGER NOT NULL,
+        PRIMARY KEY (plate, road, timestamp1, timestamp2)
+    );
+";
+
+/// Written ahead of every transaction instead of with it, and synced at checkpoints only.
+/// A crash can lose the last few commits but never corrupts the database.
+const PRAGMAS: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+";
+
+/// Most observations written in one transaction.
+const MAX_OBSERVATION_BATCH: usize = 1024;
+
+/// An observation on its way to the database, with whoever waits for it to be written.
+type QueuedObservation = (ObservationRow, oneshot::Sender<()>);
+
+/// plate, road, timestamp, mile and speed limit, as stored in the observations table.
+type ObservationRow = (Plate, Road, Timestamp, Mile, Limit);
+
+/// Everything that was persisted by a previous run of the daemon.
+#[derive(Debug, Default)]
+pub struct PersistedState {
+    pub plate_road_timestamp_camera: PlateRoadTimestampCameraDb,
+    pub issued_tickets_day: IssuedTicketsDayDb,
+    pub pending_tickets: Vec<OutboundMessageType>,
+}
+
+/// SQLite-backed durable store for observations, issued ticket days and
+/// tickets that have not been handed to a dispatcher yet.
+///
+/// The in-memory maps of [`SqliteStorage`] remain the source of truth while the
+/// daemon runs, this store is written through on every change and read back
+/// once at startup.
+///
+/// Observations are written by a task of their own, which commits whatever has queued up
+/// meanwhile in a single transaction, so cameras on every shard share the cost of a commit.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    conn: Connection,
+    observations: mpsc::Sender<QueuedObservation>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path` and makes sure the schema exists.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, SpeedDaemonError> {
+        let conn = Connection::open(path).await?;
+
+        conn.call(|conn| {
+            conn.execute_batch(PRAGMAS)?;
+            conn.execute_batch(SCHEMA)
+        })
+        .await?;
+
+        let (observations, queued) = mpsc::channel(MAX_OBSERVATION_BATCH);
+        tokio::spawn(write_observations(conn.clone(), queued));
+
+        Ok(Self { conn, observations })
+    }
+
+    pub async fn load(&self) -> Result<PersistedState, SpeedDaemonError> {
+        let persisted = self
+            .conn
+            .call(|conn| {
+                let mut persisted = PersistedState::default();
+
+                let mut stmt = conn.prepare(
+                    "SELECT plate, road, timestamp, mile, speed_limit FROM observations ORDER BY timestamp",
+                )?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    let plate_road = PlateRoadStruct::new(row.get(0)?, row.get(1)?);
+                    let ts_camera = TimestampCameraStruct {
+                        timestamp: row.get(2)?,
+                        camera: InboundMessageType::IAmCamera {
+                            road: plate_road.road,
+                            mile: row.get(3)?,
+                            limit: row.get(4)?,
+                        },
+                    };
+
+                    // rows come back ordered by timestamp so a push keeps every Vec sorted
+                    persisted
+                        .plate_road_timestamp_camera
+                        .entry(plate_road)
+                        .or_default()
+                        .push(ts_camera);
+                }
+
+                let mut stmt = conn.prepare("SELECT plate, road, day FROM issued_ticket_days")?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    let plate_road = PlateRoadStruct::new(row.get(0)?, row.get(1)?);
+                    let day: Day = row.get(2)?;
+
+                    persisted
+                        .issued_tickets_day
+                        .entry(plate_road)
+                        .or_default()
+                        .insert(day);
+                }
+
+                let mut stmt = conn.prepare(
+                    "SELECT plate, road, mile1, timestamp1, mile2, timestamp2, speed FROM pending_tickets",
+                )?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    persisted.pending_tickets.push(ticket_from_row(row)?);
+                }
+
+                Ok::<_, rusqlite::Error>(persisted)
+            })
+            .await?;
+
+        Ok(persisted)
+    }
+
+    /// Every pending ticket for `road`, including the ones no longer held in memory.
+    pub async fn load_pending_tickets(
+        &self,
+        road: Road,
+    ) -> Result<Vec<OutboundMessageType>, SpeedDaemonError> {
+        let tickets = self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT plate, road, mile1, timestamp1, mile2, timestamp2, speed FROM pending_tickets
+                     WHERE road = ?1",
+                )?;
+                let mut rows = stmt.query(params![road])?;
+
+                let mut tickets = Vec::new();
+                while let Some(row) = rows.next()? {
+                    tickets.push(ticket_from_row(row)?);
+                }
+
+                Ok::<_, rusqlite::Error>(tickets)
+            })
+            .await?;
+
+        Ok(tickets)
+    }
+
+    /// Returns once the observation is written, along with whatever else was queued meanwhile.
+    /// Failures are logged by the writer.
+    pub async fn insert_observation(
+        &self,
+        plate_road: &PlateRoadStruct,
+        ts_camera: &TimestampCameraStruct,
+    ) {
+        if let InboundMessageType::IAmCamera { road, mile, limit } = ts_camera.camera {
+            let row = (
+                plate_road.plate.clone(),
+                road,
+                ts_camera.timestamp,
+                mile,
+                limit,
+            );
+            let (written, wait) = oneshot::channel();
+
+            if self.observations.send((row, written)).await.is_ok() {
+                let _ = wait.await;
+            }
+        }
+    }
+
+    /// Deletes evicted observations, all in one transaction.
+    pub async fn remove_observations(
+        &self,
+        observations: Vec<(PlateRoadStruct, Timestamp)>,
+    ) -> Result<(), SpeedDaemonError> {
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut stmt = tx.prepare(
+                        "DELETE FROM observations WHERE plate = ?1 AND road = ?2 AND timestamp = ?3",
+                    )?;
+                    for (plate_road, timestamp) in observations {
+                        stmt.execute(params![plate_road.plate, plate_road.road, timestamp])?;
+                    }
+                }
+                tx.commit()
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records the days a car was ticketed for, all in one transaction.
+    pub async fn insert_issued_days(
+        &self,
+        plate_road: &PlateRoadStruct,
+        days: Vec<Day>,
+    ) -> Result<(), SpeedDaemonError> {
+        let plate_road = plate_road.clone();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut stmt = tx.prepare(
+                        "INSERT OR IGNORE INTO issued_ticket_days (plate, road, day) VALUES (?1, ?2, ?3)",
+                    )?;
+                    for day in days {
+                        stmt.execute(params![plate_road.plate, plate_road.road, day])?;
+                    }
+                }
+                tx.commit()
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forgets, for each road, every ticketed day before the given one, all in one transaction.
+    pub async fn remove_issued_days_before(
+        &self,
+        oldest_days: Vec<(Road, Day)>,
+    ) -> Result<(), SpeedDaemonError> {
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut stmt =
+                        tx.prepare("DELETE FROM issued_ticket_days WHERE road = ?1 AND day < ?2")?;
+                    for (road, oldest) in oldest_days {
+                        stmt.execute(params![road, oldest])?;
+                    }
+                }
+                tx.commit()
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn insert_pending_ticket(
+        &self,
+        ticket: &OutboundMessageType,
+    ) -> Result<(), SpeedDaemonError> {
+        if let OutboundMessageType::Ticket {
+            plate,
+            road,
+            mile1,
+            timestamp1,
+            mile2,
+            timestamp2,
+            speed,
+        } = ticket.clone()
+        {
+            self.conn
+                .call(move |conn| {
+                    conn.execute(
+                        "INSERT OR IGNORE INTO pending_tickets
+                         (plate, road, mile1, timestamp1, mile2, timestamp2, speed)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                        params![plate, road, mile1, timestamp1, mile2, timestamp2, speed],
+                    )
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn remove_pending_ticket(
+        &self,
+        ticket: &OutboundMessageType,
+    ) -> Result<(), SpeedDaemonError> {
+        if let OutboundMessageType::Ticket {
+            plate,
+            road,
+            timestamp1,
+            timestamp2,
+            ..
+        } = ticket.clone()
+        {
+            self.conn
+                .call(move |conn| {
+                    conn.execute(
+                        "DELETE FROM pending_tickets
+                         WHERE plate = ?1 AND road = ?2 AND timestamp1 = ?3 AND timestamp2 = ?4",
+                        params![plate, road, timestamp1, timestamp2],
+                    )
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes queued observations until every [`SqliteStore`] is gone, as many per transaction
+/// as have queued up while the previous one was being committed.
+async fn write_observations(conn: Connection, mut queued: mpsc::Receiver<QueuedObservation>) {
+    let mut batch = Vec::with_capacity(MAX_OBSERVATION_BATCH);
+
+    while queued.recv_many(&mut batch, MAX_OBSERVATION_BATCH).await > 0 {
+        let (rows, waiting): (Vec<ObservationRow>, Vec<_>) = batch.drain(..).unzip();
+        let count = rows.len();
+
+        let written = conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut stmt = tx.prepare(
+                        "INSERT OR IGNORE INTO observations (plate, road, timestamp, mile, speed_limit)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                    )?;
+                    for (plate, road, timestamp, mile, limit) in rows {
+                        stmt.execute(params![plate, road, timestamp, mile, limit])?;
+                    }
+                }
+                tx.commit()
+            })
+            .await;
+        if let Err(e) = written {
+            error!("Unable to persist {} observations: {}", count, e);
+        }
+
+        for written in waiting {
+            let _ = written.send(());
+        }
+    }
+}
+
+fn ticket_from_row(row: &Row) -> rusqlite::Result<OutboundMessageType> {
+    Ok(OutboundMessageType::Ticket {
+        plate: row.get(0)?,
+        road: row.get(1)?,
+        mile1: row.get(2)?,
+        timestamp1: row.get(3)?,
+        mile2: row.get(4)?,
+        timestamp2: row.get(5)?,
+        speed: row.get(6)?,
+    })
+}
+
+/// Durable backend: a [`MemoryStorage`] that writes every observation, issued ticket day
+/// and undelivered ticket through to a [`SqliteStore`], and is rebuilt from it at startup.
+///
+/// At most `pending_limit` undelivered tickets are held in memory, the others are spilled:
+/// they only live in the database until a dispatcher for their road connects.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    memory: MemoryStorage,
+    store: SqliteStore,
+    pending_limit: usize,
+    /// Number of spilled tickets per road.
+    spilled: Mutex<HashMap<Road, usize>>,
+    /// Held while a road's pending tickets change, striped like the shards. Queueing a ticket
+    /// and persisting it are two steps, and a dispatcher connecting in between would flush it
+    /// from memory before it's on disk, where it would stay to be delivered again after a restart.
+    road_locks: Box<[Mutex<()>]>,
+}
+
+impl SqliteStorage {
+    /// Opens the SQLite database at `path`, reloading everything a previous run has persisted there
+    /// into a [`MemoryStorage`] with `shards` shards. Pending tickets beyond `pending_limit` stay on disk.
+    pub async fn open<P: AsRef<Path>>(
+        path: P,
+        shards: usize,
+        pending_limit: usize,
+    ) -> Result<Self, SpeedDaemonError> {
+        let store = SqliteStore::open(path).await?;
+        let mut persisted = store.load().await?;
+
+        let mut spilled: HashMap<Road, usize> = HashMap::new();
+        let overflow = persisted
+            .pending_tickets
+            .split_off(pending_limit.min(persisted.pending_tickets.len()));
+        for ticket in overflow {
+            if let OutboundMessageType::Ticket { road, .. } = ticket {
+                *spilled.entry(road).or_default() += 1;
+            }
+        }
+
+        Ok(Self {
+            memory: MemoryStorage::with_history(
+                shards,
+                persisted.plate_road_timestamp_camera,
+                persisted.issued_tickets_day,
+                persisted.pending_tickets,
+            ),
+            store,
+            pending_limit,
+            spilled: Mutex::new(spilled),
+            road_locks: (0..shards.max(1)).map(|_| Mutex::new(())).collect(),
+        })
+    }
+
+    async fn lock_road(&self, road: Road) -> MutexGuard<'_, ()> {
+        self.road_locks[shard_index(road, self.road_locks.len())]
+            .lock()
+            .await
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn add_plate_road_timestamp_camera(
+        &self,
+        plate_road: PlateRoadStruct,
+        ts_camera: TimestampCameraStruct,
+    ) -> Result<(), SpeedDaemonError> {
+        // memory turns duplicates away before they reach the database
+        self.memory
+            .add_plate_road_timestamp_camera(plate_road.clone(), ts_camera.clone())
+            .await?;
+
+        self.store.insert_observation(&plate_road, &ts_camera).await;
+
+        Ok(())
+    }
+
+    async fn evict_observations(&self, policy: &RetentionPolicy) -> usize {
+        let evicted = self.memory.evict(policy).await;
+        let count = evicted.observations.len();
+
+        if count > 0 {
+            if let Err(e) = self.store.remove_observations(evicted.observations).await {
+                error!("Unable to delete {} evicted observations: {}", count, e);
+            }
+        }
+
+        if !evicted.oldest_days.is_empty() {
+            if let Err(e) = self
+                .store
+                .remove_issued_days_before(evicted.oldest_days)
+                .await
+            {
+                error!("Unable to delete expired ticket days: {}", e);
+            }
+        }
+
+        count
+    }
+
+    async fn get_ticket_for_plate(
+        &self,
+        plate_road: &PlateRoadStruct,
+        new_observation: &TimestampCameraStruct,
+        policy: &TicketPolicy,
+    ) -> Option<Vec<OutboundMessageType>> {
+        let tickets = self
+            .memory
+            .get_ticket_for_plate(plate_road, new_observation, policy)
+            .await?;
+
+        let days: Vec<Day> = tickets.iter().flat_map(ticket_days).collect();
+        if !days.is_empty() {
+            if let Err(e) = self.store.insert_issued_days(plate_road, days).await {
+                error!("Unable to persist ticket days for {:?}: {}", plate_road, e);
+            }
+        }
+
+        Some(tickets)
+    }
+
+    async fn add_camera(&self, addr: SocketAddr, new_camera: InboundMessageType) {
+        self.memory.add_camera(addr, new_camera).await
+    }
+
+    async fn get_current_camera(&self, addr: &SocketAddr) -> Option<InboundMessageType> {
+        self.memory.get_current_camera(addr).await
+    }
+
+    async fn remove_camera(&self, addr: &SocketAddr) {
+        self.memory.remove_camera(addr).await
+    }
+
+    async fn ticket_dispatcher_already_exists(&self, addr: &SocketAddr) -> bool {
+        self.memory.ticket_dispatcher_already_exists(addr).await
+    }
+
+    async fn add_ticket_dispatcher(
+        &self,
+        road: Road,
+        addr: SocketAddr,
+        tx: mpsc::Sender<OutboundMessageType>,
+    ) -> Vec<OutboundMessageType> {
+        let _road = self.lock_road(road).await;
+        let mut flushed = self.memory.add_ticket_dispatcher(road, addr, tx).await;
+
+        // Spilled tickets were never handed back to memory, fetch them from disk.
+        if self.spilled.lock().await.remove(&road).is_some() {
+            match self.store.load_pending_tickets(road).await {
+                Ok(stored) => {
+                    for ticket in stored {
+                        if !flushed.contains(&ticket) {
+                            flushed.push(ticket);
+                        }
+                    }
+                }
+                Err(e) => error!("Unable to load spilled tickets for road {}: {}", road, e),
+            }
+        }
+
+        for ticket in flushed.iter() {
+            if let Err(e) = self.store.remove_pending_ticket(ticket).await {
+                error!("Unable to remove pending ticket {:?}: {}", ticket, e);
+            }
+        }
+
+        flushed
+    }
+
+    async fn remove_ticket_dispatcher(&self, addr: &SocketAddr) {
+        self.memory.remove_ticket_dispatcher(addr).await
+    }
+
+    async fn get_ticket_dispatcher(
+        &self,
+        road: &Road,
+    ) -> Option<mpsc::Sender<OutboundMessageType>> {
+        self.memory.get_ticket_dispatcher(road).await
+    }
+
+    async fn dispatch_or_queue_ticket(
+        &self,
+        ticket: &OutboundMessageType,
+    ) -> Option<(SocketAddr, mpsc::Sender<OutboundMessageType>)> {
+        let OutboundMessageType::Ticket { road, .. } = ticket else {
+            return None;
+        };
+
+        let _road = self.lock_road(*road).await;
+        let dispatcher = self.memory.dispatch_or_queue_ticket(ticket).await;
+
+        if dispatcher.is_none() {
+            if let Err(e) = self.store.insert_pending_ticket(ticket).await {
+                error!("Unable to persist pending ticket {:?}: {}", ticket, e);
+                return None;
+            }
+
+            // The ticket is safe on disk, memory only has to hold a bounded number of them.
+            if self.memory.pending_ticket_count().await > self.pending_limit {
+                self.memory.forget_pending_ticket(ticket).await;
+                *self.spilled.lock().await.entry(*road).or_default() += 1;
+            }
+        }
+
+        dispatcher
+    }
+
+    async fn pending_tickets(&self) -> Vec<OutboundMessageType> {
+        self.memory.pending_tickets().await
+    }
+
+    async fn pending_tickets_by_road(&self) -> HashMap<Road, usize> {
+        let mut counts = self.memory.pending_tickets_by_road().await;
+        for (road, spilled) in self.spilled.lock().await.iter() {
+            *counts.entry(*road).or_default() += spilled;
+        }
+
+        counts
+    }
+
+    async fn queue_depths(&self) -> QueueDepths {
+        QueueDepths {
+            spilled_tickets: self.spilled.lock().await.values().sum(),
+            ..self.memory.queue_depths().await
+        }
+    }
+
+    async fn snapshot(&self) -> StateSnapshot {
+        let spilled = self.spilled.lock().await.clone();
+
+        StateSnapshot {
+            spilled_tickets: spilled.into_iter().collect(),
+            ..self.memory.snapshot().await
+        }
+    }
+}
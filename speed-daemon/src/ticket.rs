@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+
+use crate::{
+    message::{InboundMessageType, OutboundMessageType},
+    policy::{Rounding, TicketPolicy},
+    types::{Day, Limit, Mile, PlateRoadStruct, Timestamp, TimestampCameraStruct},
+};
+
+const SECONDS_PER_DAY: Timestamp = 86400;
+const SECONDS_PER_HOUR: u64 = 3600;
+
+/// Returns the day a timestamp falls on, as defined by the spec: floor(timestamp / 86400).
+pub fn day(timestamp: Timestamp) -> Day {
+    timestamp / SECONDS_PER_DAY
+}
+
+/// Every day covered by a ticket, from the day of its first observation to the day of its last one.
+pub fn ticket_days(ticket: &OutboundMessageType) -> Vec<Day> {
+    match ticket {
+        OutboundMessageType::Ticket {
+            timestamp1,
+            timestamp2,
+            ..
+        } => (day(*timestamp1)..=day(*timestamp2)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn mile_and_limit(observation: &TimestampCameraStruct) -> (Mile, Limit) {
+    match observation.camera {
+        InboundMessageType::IAmCamera { mile, limit, .. } => (mile, limit),
+        _ => (0, 0),
+    }
+}
+
+/// An average speed kept as the exact fraction miles / seconds, so nothing is lost
+/// until it's rounded for the ticket. All the arithmetic is done in integers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AverageSpeed {
+    pub miles: u32,
+    pub seconds: u32,
+}
+
+impl AverageSpeed {
+    /// Speed in whole mph, rounded according to `rounding` (`Nearest` rounds halves up).
+    pub fn mph(&self, rounding: Rounding) -> u64 {
+        divide(
+            self.miles as u64 * SECONDS_PER_HOUR,
+            self.seconds as u64,
+            rounding,
+        )
+    }
+
+    /// Speed in hundredths of mph, as the spec reports it on tickets, rounded to the nearest hundredth.
+    pub fn hundredths(&self) -> u64 {
+        divide(
+            self.miles as u64 * SECONDS_PER_HOUR * 100,
+            self.seconds as u64,
+            Rounding::Nearest,
+        )
+    }
+}
+
+fn divide(numerator: u64, denominator: u64, rounding: Rounding) -> u64 {
+    match rounding {
+        Rounding::Nearest => (2 * numerator + denominator) / (2 * denominator),
+        Rounding::Down => numerator / denominator,
+        Rounding::Up => numerator.div_ceil(denominator),
+    }
+}
+
+/// Average speed between two observations.
+/// Returns `None` when both were taken at the same second, there's no speed to speak of.
+pub fn calculate_average_speed(
+    observation1: &TimestampCameraStruct,
+    observation2: &TimestampCameraStruct,
+) -> Option<AverageSpeed> {
+    let (mile1, _) = mile_and_limit(observation1);
+    let (mile2, _) = mile_and_limit(observation2);
+
+    let seconds = observation1.timestamp.abs_diff(observation2.timestamp);
+    if seconds == 0 {
+        return None;
+    }
+
+    Some(AverageSpeed {
+        miles: mile1.abs_diff(mile2) as u32,
+        seconds,
+    })
+}
+
+/// Checks a pair of observations, `earlier` being the one with the smaller timestamp.
+/// Issues a ticket if `policy` says the car was speeding and none of the days the pair spans
+/// has been ticketed yet, in which case all of those days are recorded in `issued_days`.
+fn check_pair(
+    plate_road: &PlateRoadStruct,
+    earlier: &TimestampCameraStruct,
+    later: &TimestampCameraStruct,
+    policy: &TicketPolicy,
+    issued_days: &mut HashSet<Day>,
+) -> Option<OutboundMessageType> {
+    // The speed limit is common across both observations since there's only 1 limit per road
+    let (mile1, limit) = mile_and_limit(earlier);
+    let (mile2, _) = mile_and_limit(later);
+
+    let average_speed = calculate_average_speed(earlier, later)?;
+    let speed = policy.ticket_speed(average_speed, limit)?;
+
+    let days = day(earlier.timestamp)..=day(later.timestamp);
+    if days.clone().any(|day| issued_days.contains(&day)) {
+        // warn!("{:?} was previously issued a ticket within {:?}, no ticket.", plate_road, days);
+        return None;
+    }
+    issued_days.extend(days);
+
+    Some(OutboundMessageType::Ticket {
+        plate: plate_road.plate.clone(),
+        road: plate_road.road,
+        mile1,
+        timestamp1: earlier.timestamp,
+        mile2,
+        timestamp2: later.timestamp,
+        speed,
+    })
+}
+
+/// Evaluates a newly added observation against its chronological neighbours only:
+/// the observation right before it and the one right after it in `observations`,
+/// which must be sorted by timestamp and already contain `new_observation`.
+///
+/// Every pair further apart was already checked when its own observations arrived,
+/// so the whole history doesn't need to be rescanned.
+pub fn check_new_observation(
+    plate_road: &PlateRoadStruct,
+    observations: &[TimestampCameraStruct],
+    new_observation: &TimestampCameraStruct,
+    policy: &TicketPolicy,
+    issued_days: &mut HashSet<Day>,
+) -> Vec<OutboundMessageType> {
+    let mut tickets = Vec::new();
+
+    let Ok(position) = observations.binary_search(new_observation) else {
+        return tickets;
+    };
+
+    if position > 0 {
+        if let Some(ticket) = check_pair(
+            plate_road,
+            &observations[position - 1],
+            &observations[position],
+            policy,
+            issued_days,
+        ) {
+            tickets.push(ticket);
+        }
+    }
+
+    if let Some(next) = observations.get(position + 1) {
+        if let Some(ticket) = check_pair(
+            plate_road,
+            &observations[position],
+            next,
+            policy,
+            issued_days,
+        ) {
+            tickets.push(ticket);
+        }
+    }
+
+    tickets
+}
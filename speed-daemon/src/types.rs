@@ -1,4 +1,9 @@
-use std::{collections::{HashMap, HashSet}, hash::Hash, net::SocketAddr, cmp::Ordering};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    net::SocketAddr,
+};
 
 use tokio::sync::mpsc;
 
@@ -36,7 +41,6 @@ impl PartialEq for TimestampCameraStruct {
     }
 }
 
-
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct PlateRoadStruct {
     pub plate: Plate,
@@ -49,6 +53,58 @@ impl PlateRoadStruct {
     }
 }
 
+/// All the dispatchers responsible for a single road.
+/// Tickets are handed out to them in turn (round-robin).
+#[derive(Clone, Debug, Default)]
+pub struct RoadDispatchers {
+    dispatchers: Vec<(SocketAddr, mpsc::Sender<OutboundMessageType>)>,
+    next: usize,
+}
+
+impl RoadDispatchers {
+    pub fn add(&mut self, addr: SocketAddr, tx: mpsc::Sender<OutboundMessageType>) {
+        // a re-registration from the same client replaces its old channel
+        self.remove(&addr);
+        self.dispatchers.push((addr, tx));
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.dispatchers
+            .retain(|(client_addr, _)| client_addr != addr);
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.dispatchers
+            .iter()
+            .any(|(client_addr, _)| client_addr == addr)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dispatchers.is_empty()
+    }
+
+    /// How many messages are waiting to be written to each dispatcher.
+    pub fn queue_depths(&self) -> impl Iterator<Item = (SocketAddr, usize)> + '_ {
+        self.dispatchers
+            .iter()
+            .map(|(addr, tx)| (*addr, tx.max_capacity() - tx.capacity()))
+    }
+
+    /// Returns the next dispatcher in the rotation, dropping any whose connection has already gone away.
+    pub fn next_dispatcher(&mut self) -> Option<(SocketAddr, mpsc::Sender<OutboundMessageType>)> {
+        self.dispatchers.retain(|(_, tx)| !tx.is_closed());
+
+        if self.dispatchers.is_empty() {
+            return None;
+        }
+
+        let index = self.next % self.dispatchers.len();
+        self.next = index + 1;
+
+        self.dispatchers.get(index).cloned()
+    }
+}
+
 // ----------------Shared state data structures----------------
 // A hash of Plate -> (timestamp, IAmCamera)
 // pub type PlateCameraDb = HashMap<Plate, TimestampCameraTuple>;
@@ -59,8 +115,8 @@ impl PlateRoadStruct {
 // This contains the Plate -> Vec<Timestamp,Camera> mapping
 pub type PlateRoadTimestampCameraDb = HashMap<PlateRoadStruct, Vec<TimestampCameraStruct>>;
 
-// This maps a road ID to a hash of IP,tx
-pub type TicketDispatcherDb = HashMap<Road, HashMap<SocketAddr, mpsc::Sender<OutboundMessageType>>>;
+// This maps a road ID to every dispatcher responsible for it
+pub type TicketDispatcherDb = HashMap<Road, RoadDispatchers>;
 
 // Since we don't allow more than one ticket pre day we need to store the tickets.
 // This is a hash of Plate -> Ticket. The ticket includes the timestamp so we can calculate when the last ticket was issued.
@@ -70,6 +126,10 @@ pub type PlateTicketDb = HashMap<Plate, OutboundMessageType>;
 // we need to check the camera's mile marker and speed limit to calculate the avg speed.
 pub type CurrentCameraDb = HashMap<SocketAddr, InboundMessageType>;
 
+// Tickets that could not be delivered yet because no dispatcher is connected for their road.
+// They are flushed to the first dispatcher that registers for the road.
+pub type PendingTicketDb = HashMap<Road, Vec<OutboundMessageType>>;
+
 // This keeps a mapping Plate to a Vec of days, where days are defined by floor(timestamp / 86400)
 // i.e. a plate "FOO" could have been ticketed on multiple days
 // Every day that contributed to a ticket gets stored, unique values only.
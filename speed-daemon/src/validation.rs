@@ -0,0 +1,39 @@
+use crate::{errors::SpeedDaemonError, message::InboundMessageType, types::Limit};
+
+/// Checks a message the parsers accepted before any handler acts on it.
+///
+/// The parsers only know about the wire format. This is where values that are well-formed
+/// but make no sense are turned away: plates that aren't uppercase alphanumeric and cameras
+/// on roads with a speed limit of 0.
+pub fn validate(message: InboundMessageType) -> Result<InboundMessageType, SpeedDaemonError> {
+    match &message {
+        InboundMessageType::Plate { plate, .. } => validate_plate(plate)?,
+        InboundMessageType::IAmCamera { limit, .. } => validate_limit(*limit)?,
+        InboundMessageType::WantHeartbeat { .. } | InboundMessageType::IAmDispatcher { .. } => {}
+    }
+
+    Ok(message)
+}
+
+/// A plate is a non-empty string of uppercase ASCII letters and digits.
+pub fn validate_plate(plate: &str) -> Result<(), SpeedDaemonError> {
+    let valid = !plate.is_empty()
+        && plate
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+
+    if !valid {
+        return Err(SpeedDaemonError::InvalidPlate(plate.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Every car would be speeding on a road with a limit of 0.
+pub fn validate_limit(limit: Limit) -> Result<(), SpeedDaemonError> {
+    if limit == 0 {
+        return Err(SpeedDaemonError::InvalidLimit);
+    }
+
+    Ok(())
+}
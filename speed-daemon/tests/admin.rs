@@ -0,0 +1,321 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use speed_daemon::{admin, client::Client, message::OutboundMessageType, metrics::Metrics};
+
+mod common;
+
+use common::TestServer;
+
+/// Long enough for anything the server is supposed to send.
+const PATIENCE: Duration = Duration::from_secs(5);
+
+/// Long enough for the server to have handled what the clients sent.
+const SETTLE: Duration = Duration::from_millis(300);
+
+/// Starts a server and its admin endpoint sharing in-memory storage, both on ephemeral ports.
+/// Returns the server's address, then the admin endpoint's.
+async fn start_servers() -> (SocketAddr, SocketAddr) {
+    let server = TestServer::start().await;
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+
+    tokio::spawn(admin::run(admin_listener, server.shared_db));
+
+    (server.addr, admin_addr)
+}
+
+/// Sends `request` as is and returns the status line and the body of the response.
+async fn request(admin_addr: SocketAddr, request: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(admin_addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    timeout(PATIENCE, stream.read_to_string(&mut response))
+        .await
+        .expect("the response never ended")
+        .unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").expect("no end of headers");
+    let status = head.lines().next().unwrap_or_default().to_string();
+    (status, body.to_string())
+}
+
+async fn get_state(admin_addr: SocketAddr) -> Value {
+    let (status, body) = request(
+        admin_addr,
+        "GET /state HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n",
+    )
+    .await;
+    assert_eq!(status, "HTTP/1.1 200 OK", "{body}");
+
+    serde_json::from_str(&body).unwrap()
+}
+
+#[tokio::test]
+async fn an_idle_server_is_empty() {
+    let (_, admin_addr) = start_servers().await;
+
+    assert_eq!(
+        get_state(admin_addr).await,
+        json!({
+            "cameras": [],
+            "dispatchers": {},
+            "pending_tickets": [],
+            "spilled_tickets": {},
+            "observations": {},
+            "issued_ticket_days": [],
+        })
+    );
+}
+
+#[tokio::test]
+async fn state_shows_clients_observations_and_tickets() {
+    let (addr, admin_addr) = start_servers().await;
+
+    let dispatcher = Client::dispatcher(addr, vec![123, 124]).await.unwrap();
+
+    // ticketed on road 123, where the dispatcher is
+    let mut camera1 = Client::camera(addr, 123, 8, 60).await.unwrap();
+    camera1.plate("UN1X".into(), 0).await.unwrap();
+    let mut camera2 = Client::camera(addr, 123, 9, 60).await.unwrap();
+    camera2.plate("UN1X".into(), 45).await.unwrap();
+
+    // ticketed on road 7, where nobody picks it up
+    let mut camera3 = Client::camera(addr, 7, 100, 40).await.unwrap();
+    camera3.plate("RE05BKG".into(), 1000).await.unwrap();
+    camera3.plate("SLOW".into(), 1000).await.unwrap();
+    let mut camera4 = Client::camera(addr, 7, 110, 40).await.unwrap();
+    camera4.plate("RE05BKG".into(), 1600).await.unwrap();
+
+    tokio::time::sleep(SETTLE).await;
+    let state = get_state(admin_addr).await;
+
+    let cameras: Vec<_> = state["cameras"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|camera| (&camera["road"], &camera["mile"], &camera["limit"]))
+        .collect();
+    assert_eq!(
+        cameras,
+        vec![
+            (&json!(7), &json!(100), &json!(40)),
+            (&json!(7), &json!(110), &json!(40)),
+            (&json!(123), &json!(8), &json!(60)),
+            (&json!(123), &json!(9), &json!(60)),
+        ]
+    );
+
+    // the dispatcher's ticket has been written out already
+    let dispatcher_entry = &state["dispatchers"]["123"];
+    assert_eq!(dispatcher_entry[0]["queued"], json!(0));
+    assert!(dispatcher_entry[0]["addr"]
+        .as_str()
+        .unwrap()
+        .starts_with("127.0.0.1:"));
+    assert_eq!(
+        state["dispatchers"],
+        json!({ "123": dispatcher_entry, "124": dispatcher_entry })
+    );
+
+    assert_eq!(
+        state["pending_tickets"],
+        json!([{
+            "plate": "RE05BKG",
+            "road": 7,
+            "mile1": 100,
+            "timestamp1": 1000,
+            "mile2": 110,
+            "timestamp2": 1600,
+            "speed": 6000,
+        }])
+    );
+
+    assert_eq!(
+        state["observations"],
+        json!({
+            "7": { "plates": 2, "observations": 3 },
+            "123": { "plates": 1, "observations": 2 },
+        })
+    );
+
+    assert_eq!(
+        state["issued_ticket_days"],
+        json!([
+            { "plate": "RE05BKG", "road": 7, "days": [0] },
+            { "plate": "UN1X", "road": 123, "days": [0] },
+        ])
+    );
+
+    // gone clients are gone from the state too
+    drop((dispatcher, camera1, camera2, camera3, camera4));
+    tokio::time::sleep(SETTLE).await;
+    let state = get_state(admin_addr).await;
+    assert_eq!(state["cameras"], json!([]));
+    assert_eq!(state["dispatchers"], json!({}));
+}
+
+#[tokio::test]
+async fn unknown_requests_are_refused() {
+    let (_, admin_addr) = start_servers().await;
+
+    for (request_text, expected) in [
+        ("GET /nope HTTP/1.1\r\n\r\n", "HTTP/1.1 404 Not Found"),
+        (
+            "POST /state HTTP/1.1\r\n\r\n",
+            "HTTP/1.1 405 Method Not Allowed",
+        ),
+        ("GET /state\r\n\r\n", "HTTP/1.1 400 Bad Request"),
+        ("GET /state HTTP/1.1\r\n", "HTTP/1.1 400 Bad Request"),
+    ] {
+        let mut stream = TcpStream::connect(admin_addr).await.unwrap();
+        stream.write_all(request_text.as_bytes()).await.unwrap();
+        // a request that never ends has to be ended by the client
+        stream.shutdown().await.unwrap();
+
+        let mut response = String::new();
+        timeout(PATIENCE, stream.read_to_string(&mut response))
+            .await
+            .expect("the response never ended")
+            .unwrap();
+        assert!(
+            response.starts_with(expected),
+            "{request_text:?}: got {response:?}"
+        );
+    }
+
+    // the query string doesn't matter
+    let (status, _) = request(admin_addr, "GET /state?pretty HTTP/1.0\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+}
+
+/// The value of the sample `series`, e.g. `foo_total{road="1"}`, in a Prometheus text `body`.
+fn sample(body: &str, series: &str) -> f64 {
+    body.lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {series} in\n{body}"))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn metrics_count_connections_messages_and_tickets() {
+    let (addr, admin_addr) = start_servers().await;
+
+    let mut dispatcher = Client::dispatcher(addr, vec![123]).await.unwrap();
+    dispatcher.want_heartbeat(1).await.unwrap();
+
+    let mut camera1 = Client::camera(addr, 123, 8, 60).await.unwrap();
+    camera1.plate("UN1X".into(), 0).await.unwrap();
+    let mut camera2 = Client::camera(addr, 123, 9, 60).await.unwrap();
+    camera2.plate("UN1X".into(), 45).await.unwrap();
+
+    // no dispatcher for road 7, its ticket stays pending
+    let mut camera3 = Client::camera(addr, 7, 100, 40).await.unwrap();
+    camera3.plate("RE05BKG".into(), 1000).await.unwrap();
+    let mut camera4 = Client::camera(addr, 7, 110, 40).await.unwrap();
+    camera4.plate("RE05BKG".into(), 1600).await.unwrap();
+
+    let _silent = Client::connect(addr).await.unwrap();
+
+    let mut garbage = TcpStream::connect(addr).await.unwrap();
+    garbage.write_all(&[0x99]).await.unwrap();
+    let mut error = Vec::new();
+    timeout(PATIENCE, garbage.read_to_end(&mut error))
+        .await
+        .expect("the garbage sender wasn't disconnected")
+        .unwrap();
+
+    loop {
+        let message = timeout(PATIENCE, dispatcher.recv())
+            .await
+            .expect("no ticket for the dispatcher")
+            .unwrap()
+            .unwrap();
+        if message != OutboundMessageType::Heartbeat {
+            break;
+        }
+    }
+
+    tokio::time::sleep(SETTLE).await;
+    let (status, body) = request(admin_addr, "GET /metrics HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+
+    let expected = [
+        ("speed_daemon_connections_accepted_total", 7.0),
+        ("speed_daemon_connections{client=\"unidentified\"}", 1.0),
+        ("speed_daemon_connections{client=\"camera\"}", 4.0),
+        ("speed_daemon_connections{client=\"dispatcher\"}", 1.0),
+        ("speed_daemon_messages_decoded_total{type=\"plate\"}", 4.0),
+        (
+            "speed_daemon_messages_decoded_total{type=\"want_heartbeat\"}",
+            1.0,
+        ),
+        (
+            "speed_daemon_messages_decoded_total{type=\"i_am_camera\"}",
+            4.0,
+        ),
+        (
+            "speed_daemon_messages_decoded_total{type=\"i_am_dispatcher\"}",
+            1.0,
+        ),
+        ("speed_daemon_parse_failures_total", 1.0),
+        ("speed_daemon_tickets_issued_total{road=\"7\"}", 1.0),
+        ("speed_daemon_tickets_issued_total{road=\"123\"}", 1.0),
+        ("speed_daemon_tickets_delivered_total{road=\"123\"}", 1.0),
+        ("speed_daemon_tickets_pending{road=\"7\"}", 1.0),
+        ("speed_daemon_tickets_pending{road=\"123\"}", 0.0),
+        (
+            "speed_daemon_ticket_delivery_seconds_bucket{le=\"+Inf\"}",
+            1.0,
+        ),
+        ("speed_daemon_ticket_delivery_seconds_count", 1.0),
+    ];
+    for (series, value) in expected {
+        assert_eq!(sample(&body, series), value, "{series}");
+    }
+    assert!(sample(&body, "speed_daemon_heartbeats_sent_total") >= 1.0);
+    assert!(!body.contains("speed_daemon_tickets_delivered_total{road=\"7\"}"));
+}
+
+#[test]
+fn only_so_many_undelivered_tickets_are_timed() {
+    let metrics = Metrics::new();
+    let ticket = |plate: u32| OutboundMessageType::Ticket {
+        plate: format!("P{plate}"),
+        road: 7,
+        mile1: 0,
+        timestamp1: 0,
+        mile2: 100,
+        timestamp2: 3600,
+        speed: 10000,
+    };
+
+    // one more than are tracked, the first one is forgotten
+    for plate in 0..=10_000 {
+        metrics.ticket_issued(&ticket(plate));
+    }
+    metrics.message_sent(&ticket(0));
+    metrics.message_sent(&ticket(10_000));
+
+    let body = metrics.render(&HashMap::new());
+    assert_eq!(
+        sample(&body, "speed_daemon_tickets_issued_total{road=\"7\"}"),
+        10_001.0
+    );
+    assert_eq!(
+        sample(&body, "speed_daemon_tickets_delivered_total{road=\"7\"}"),
+        2.0
+    );
+    assert_eq!(
+        sample(&body, "speed_daemon_ticket_delivery_seconds_count"),
+        1.0
+    );
+}
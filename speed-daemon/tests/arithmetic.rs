@@ -0,0 +1,108 @@
+use proptest::prelude::*;
+
+use speed_daemon::{
+    message::InboundMessageType,
+    policy::Rounding,
+    ticket::{calculate_average_speed, day, AverageSpeed},
+    types::{Mile, Timestamp, TimestampCameraStruct},
+};
+
+// Reference implementations in f64, which represents every u32 and every
+// miles * 360000 product exactly, so only the final division rounds.
+
+fn reference_day(timestamp: Timestamp) -> u32 {
+    (timestamp as f64 / 86400.0).floor() as u32
+}
+
+fn reference_hundredths(miles: u32, seconds: u32) -> u64 {
+    (miles as f64 * 360000.0 / seconds as f64).round() as u64
+}
+
+fn reference_mph(miles: u32, seconds: u32, rounding: Rounding) -> u64 {
+    let mph = miles as f64 * 3600.0 / seconds as f64;
+    let rounded = match rounding {
+        Rounding::Nearest => mph.round(),
+        Rounding::Down => mph.floor(),
+        Rounding::Up => mph.ceil(),
+    };
+    rounded as u64
+}
+
+fn observation(timestamp: Timestamp, mile: Mile) -> TimestampCameraStruct {
+    TimestampCameraStruct {
+        timestamp,
+        camera: InboundMessageType::IAmCamera {
+            road: 1,
+            mile,
+            limit: 60,
+        },
+    }
+}
+
+fn rounding() -> impl Strategy<Value = Rounding> {
+    prop_oneof![
+        Just(Rounding::Nearest),
+        Just(Rounding::Down),
+        Just(Rounding::Up),
+    ]
+}
+
+#[test]
+fn last_timestamp_falls_on_the_last_day() {
+    assert_eq!(day(Timestamp::MAX), 49710);
+    assert_eq!(day(Timestamp::MAX), reference_day(Timestamp::MAX));
+}
+
+proptest! {
+    #[test]
+    fn day_matches_reference(timestamp in any::<Timestamp>()) {
+        prop_assert_eq!(day(timestamp), reference_day(timestamp));
+    }
+
+    #[test]
+    fn day_boundaries_are_exact(day_number in 1u32..=49710) {
+        let midnight = day_number * 86400;
+
+        prop_assert_eq!(day(midnight - 1), day_number - 1);
+        prop_assert_eq!(day(midnight), day_number);
+    }
+
+    #[test]
+    fn speed_matches_reference(
+        mile1 in any::<Mile>(),
+        mile2 in any::<Mile>(),
+        timestamp1 in any::<Timestamp>(),
+        timestamp2 in any::<Timestamp>(),
+        rounding in rounding(),
+    ) {
+        let speed = calculate_average_speed(
+            &observation(timestamp1, mile1),
+            &observation(timestamp2, mile2),
+        );
+
+        let seconds = timestamp1.abs_diff(timestamp2);
+        let miles = mile1.abs_diff(mile2) as u32;
+
+        if seconds == 0 {
+            prop_assert_eq!(speed, None);
+        } else {
+            let speed = speed.unwrap();
+            prop_assert_eq!(speed, AverageSpeed { miles, seconds });
+            prop_assert_eq!(speed.hundredths(), reference_hundredths(miles, seconds));
+            prop_assert_eq!(speed.mph(rounding), reference_mph(miles, seconds, rounding));
+        }
+    }
+
+    #[test]
+    fn short_intervals_match_reference(
+        miles in 0u32..=65535,
+        seconds in 1u32..=3600,
+        rounding in rounding(),
+    ) {
+        // Short intervals give the high speeds where a lossy float is most likely to round wrong.
+        let speed = AverageSpeed { miles, seconds };
+
+        prop_assert_eq!(speed.hundredths(), reference_hundredths(miles, seconds));
+        prop_assert_eq!(speed.mph(rounding), reference_mph(miles, seconds, rounding));
+    }
+}
@@ -0,0 +1,200 @@
+use bytes::BytesMut;
+use proptest::prelude::*;
+use tokio_util::codec::{Decoder, Encoder};
+
+// The fuzz targets' decoding helpers, shared so the two can't drift apart.
+#[path = "../fuzz/src/lib.rs"]
+mod decoding;
+
+use decoding::{assert_chunking_is_invisible, decode_all, decode_chunks, Decoded};
+
+use speed_daemon::{
+    codec::{ClientCodec, MessageCodec},
+    errors::SpeedDaemonError,
+    message::{InboundMessageType, OutboundMessageType},
+};
+
+// Up to 63 characters of at most 4 bytes each always fit in the length byte.
+const TEXT: &str = "\\PC{0,63}";
+
+fn inbound_message() -> impl Strategy<Value = InboundMessageType> {
+    prop_oneof![
+        (TEXT, any::<u32>())
+            .prop_map(|(plate, timestamp)| InboundMessageType::Plate { plate, timestamp }),
+        any::<u32>().prop_map(|interval| InboundMessageType::WantHeartbeat { interval }),
+        (any::<u16>(), any::<u16>(), any::<u16>())
+            .prop_map(|(road, mile, limit)| InboundMessageType::IAmCamera { road, mile, limit }),
+        prop::collection::vec(any::<u16>(), 0..=255)
+            .prop_map(|roads| InboundMessageType::IAmDispatcher { roads }),
+    ]
+}
+
+fn outbound_message() -> impl Strategy<Value = OutboundMessageType> {
+    prop_oneof![
+        Just(OutboundMessageType::Heartbeat),
+        TEXT.prop_map(OutboundMessageType::Error),
+        (
+            TEXT,
+            any::<u16>(),
+            any::<u16>(),
+            any::<u32>(),
+            any::<u16>(),
+            any::<u32>(),
+            any::<u16>(),
+        )
+            .prop_map(
+                |(plate, road, mile1, timestamp1, mile2, timestamp2, speed)| {
+                    OutboundMessageType::Ticket {
+                        plate,
+                        road,
+                        mile1,
+                        timestamp1,
+                        mile2,
+                        timestamp2,
+                        speed,
+                    }
+                }
+            ),
+    ]
+}
+
+/// Nothing but `messages`, decoded without an error.
+fn decoded<T>(messages: Vec<T>) -> Decoded<T> {
+    Decoded {
+        messages,
+        failed: false,
+    }
+}
+
+proptest! {
+    #[test]
+    fn inbound_messages_survive_byte_at_a_time_delivery(
+        messages in prop::collection::vec(inbound_message(), 0..16)
+    ) {
+        let mut buf = BytesMut::new();
+        for message in messages.iter().cloned() {
+            ClientCodec::new().encode(message, &mut buf).unwrap();
+        }
+
+        prop_assert_eq!(
+            decode_chunks(&mut MessageCodec::new(), buf.chunks(1)),
+            decoded(messages)
+        );
+    }
+
+    #[test]
+    fn outbound_messages_survive_byte_at_a_time_delivery(
+        messages in prop::collection::vec(outbound_message(), 0..16)
+    ) {
+        let mut buf = BytesMut::new();
+        for message in messages.iter().cloned() {
+            MessageCodec::new().encode(message, &mut buf).unwrap();
+        }
+
+        prop_assert_eq!(
+            decode_chunks(&mut ClientCodec::new(), buf.chunks(1)),
+            decoded(messages)
+        );
+    }
+
+    #[test]
+    fn inbound_messages_round_trip(messages in prop::collection::vec(inbound_message(), 0..16)) {
+        let mut buf = BytesMut::new();
+        for message in messages.iter().cloned() {
+            ClientCodec::new().encode(message, &mut buf).unwrap();
+        }
+
+        prop_assert_eq!(decode_all(&mut MessageCodec::new(), &buf), decoded(messages));
+    }
+
+    #[test]
+    fn chunking_is_invisible(
+        messages in prop::collection::vec(inbound_message(), 0..16),
+        sizes in prop::collection::vec(any::<u8>(), 0..16),
+    ) {
+        let mut buf = BytesMut::new();
+        for message in messages {
+            ClientCodec::new().encode(message, &mut buf).unwrap();
+        }
+
+        assert_chunking_is_invisible(MessageCodec::new, &buf, &sizes);
+    }
+
+    #[test]
+    fn outbound_messages_round_trip(messages in prop::collection::vec(outbound_message(), 0..16)) {
+        let mut buf = BytesMut::new();
+        for message in messages.iter().cloned() {
+            MessageCodec::new().encode(message, &mut buf).unwrap();
+        }
+
+        prop_assert_eq!(decode_all(&mut ClientCodec::new(), &buf), decoded(messages));
+    }
+}
+
+#[test]
+fn long_error_messages_are_truncated() {
+    let mut buf = BytesMut::new();
+    MessageCodec::new()
+        .encode(OutboundMessageType::Error("é".repeat(200)), &mut buf)
+        .unwrap();
+
+    // 127 two-byte characters fit in 255 bytes, the 128th would be split
+    assert_eq!(
+        decode_all(&mut ClientCodec::new(), &buf),
+        decoded(vec![OutboundMessageType::Error("é".repeat(127))])
+    );
+}
+
+#[test]
+fn oversized_fields_are_not_encoded() {
+    let mut buf = BytesMut::new();
+
+    let plate = InboundMessageType::Plate {
+        plate: "A".repeat(256),
+        timestamp: 0,
+    };
+    assert!(matches!(
+        ClientCodec::new().encode(plate, &mut buf),
+        Err(SpeedDaemonError::MessageTooLong("plate"))
+    ));
+
+    let dispatcher = InboundMessageType::IAmDispatcher {
+        roads: vec![0; 256],
+    };
+    assert!(matches!(
+        ClientCodec::new().encode(dispatcher, &mut buf),
+        Err(SpeedDaemonError::MessageTooLong("roads"))
+    ));
+}
+
+#[test]
+fn split_frames_wait_for_the_rest() {
+    // IAmDispatcher for roads 66 and 368, cut after the first byte of the second road
+    let mut buf = BytesMut::from(&[0x81, 0x02, 0x00, 0x42, 0x01][..]);
+    assert_eq!(MessageCodec::new().decode(&mut buf).unwrap(), None);
+    assert_eq!(buf.len(), 5, "nothing is consumed until the frame is whole");
+
+    buf.extend_from_slice(&[0x70]);
+    assert_eq!(
+        MessageCodec::new().decode(&mut buf).unwrap(),
+        Some(InboundMessageType::IAmDispatcher {
+            roads: vec![66, 368]
+        })
+    );
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn unknown_message_types_fail_without_waiting() {
+    let mut buf = BytesMut::from(&[0x99][..]);
+    assert!(matches!(
+        MessageCodec::new().decode(&mut buf),
+        Err(SpeedDaemonError::ParseFailure)
+    ));
+
+    let mut buf = BytesMut::from(&[0x20][..]);
+    assert!(matches!(
+        ClientCodec::new().decode(&mut buf),
+        Err(SpeedDaemonError::ParseFailure)
+    ));
+}
@@ -0,0 +1,51 @@
+//! Fixtures shared by the integration tests. Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener;
+
+use speed_daemon::{
+    engine::TicketEngine,
+    server::{self, Shutdown},
+    state::Db,
+};
+
+/// A server with in-memory storage running on an ephemeral port.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub shared_db: Db,
+    pub ticket_engine: TicketEngine,
+    /// Shuts the server down.
+    pub shutdown: Shutdown,
+}
+
+impl TestServer {
+    pub async fn start() -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let shared_db = Db::new();
+        let ticket_engine = TicketEngine::spawn(shared_db.clone(), 1024, 4);
+        let shutdown = Shutdown::new();
+        tokio::spawn(server::run(
+            listener,
+            shared_db.clone(),
+            ticket_engine.clone(),
+            1024,
+            shutdown.clone(),
+        ));
+
+        TestServer {
+            addr,
+            shared_db,
+            ticket_engine,
+            shutdown,
+        }
+    }
+}
+
+/// Starts a [`TestServer`] that's only ever talked to over the network.
+pub async fn start_server() -> SocketAddr {
+    TestServer::start().await.addr
+}
@@ -0,0 +1,152 @@
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc;
+
+use speed_daemon::{message::OutboundMessageType, queue::SlowDispatcherPolicy, state::Db};
+
+const ROAD: u16 = 1;
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn ticket(plate: &str) -> OutboundMessageType {
+    OutboundMessageType::Ticket {
+        plate: plate.to_string(),
+        road: ROAD,
+        mile1: 0,
+        timestamp1: 0,
+        mile2: 100,
+        timestamp2: 3600,
+        speed: 10000,
+    }
+}
+
+#[tokio::test]
+async fn a_slow_dispatcher_is_disconnected() {
+    let shared_db = Db::new().with_slow_dispatcher(SlowDispatcherPolicy::Drop);
+
+    let slow = client(1000);
+    let dropped = shared_db.track_client(slow);
+    let (tx, mut rx) = mpsc::channel(1);
+    shared_db.add_ticket_dispatcher(ROAD, slow, tx).await;
+
+    shared_db.dispatch_ticket(ticket("FIRST")).await;
+    assert!(!dropped.is_cancelled());
+
+    // the queue is full now
+    shared_db.dispatch_ticket(ticket("SECOND")).await;
+    assert!(dropped.is_cancelled(), "the dispatcher is still connected");
+    assert!(!shared_db.ticket_dispatcher_already_exists(&slow).await);
+    assert_eq!(shared_db.pending_tickets().await, vec![ticket("SECOND")]);
+
+    // what it already had is still delivered
+    assert_eq!(rx.recv().await, Some(ticket("FIRST")));
+
+    // reconnecting picks up what piled up meanwhile
+    shared_db.untrack_client(&slow);
+    let (tx, _rx) = mpsc::channel(1);
+    let flushed = shared_db.add_ticket_dispatcher(ROAD, slow, tx).await;
+    assert_eq!(flushed, vec![ticket("SECOND")]);
+}
+
+#[tokio::test]
+async fn a_blocked_dispatcher_is_waited_for() {
+    let shared_db = Db::new().with_slow_dispatcher(SlowDispatcherPolicy::Block);
+
+    let slow = client(1000);
+    let dropped = shared_db.track_client(slow);
+    let (tx, mut rx) = mpsc::channel(1);
+    shared_db.add_ticket_dispatcher(ROAD, slow, tx).await;
+
+    shared_db.dispatch_ticket(ticket("FIRST")).await;
+    let blocked = tokio::spawn({
+        let shared_db = shared_db.clone();
+        async move { shared_db.dispatch_ticket(ticket("SECOND")).await }
+    });
+
+    assert_eq!(rx.recv().await, Some(ticket("FIRST")));
+    assert_eq!(rx.recv().await, Some(ticket("SECOND")));
+    blocked.await.unwrap();
+    assert!(!dropped.is_cancelled());
+}
+
+#[tokio::test]
+async fn tickets_go_round_robin_and_survive_a_dispatcher_leaving() {
+    let shared_db = Db::new();
+
+    let (first, second) = (client(1000), client(1001));
+    let (tx, mut first_rx) = mpsc::channel(16);
+    shared_db.add_ticket_dispatcher(ROAD, first, tx).await;
+    let (tx, mut second_rx) = mpsc::channel(16);
+    shared_db.add_ticket_dispatcher(ROAD, second, tx).await;
+
+    for plate in ["A1", "B2", "C3", "D4"] {
+        shared_db.dispatch_ticket(ticket(plate)).await;
+    }
+
+    // each gets every other ticket, whichever goes first
+    let mut firsts = vec![
+        first_rx.recv().await.unwrap(),
+        first_rx.recv().await.unwrap(),
+    ];
+    let mut seconds = vec![
+        second_rx.recv().await.unwrap(),
+        second_rx.recv().await.unwrap(),
+    ];
+    if firsts[0] != ticket("A1") {
+        std::mem::swap(&mut firsts, &mut seconds);
+    }
+    assert_eq!(firsts, vec![ticket("A1"), ticket("C3")]);
+    assert_eq!(seconds, vec![ticket("B2"), ticket("D4")]);
+
+    // the first one disconnects, the other one gets everything from now on
+    drop(first_rx);
+    for plate in ["E5", "F6", "G7"] {
+        shared_db.dispatch_ticket(ticket(plate)).await;
+    }
+    for plate in ["E5", "F6", "G7"] {
+        assert_eq!(second_rx.recv().await, Some(ticket(plate)));
+    }
+    assert!(!shared_db.ticket_dispatcher_already_exists(&first).await);
+    assert!(shared_db.pending_tickets().await.is_empty());
+}
+
+#[tokio::test]
+async fn a_flush_cut_short_loses_no_tickets() {
+    let shared_db = Db::new();
+    for plate in ["A1", "B2", "C3"] {
+        shared_db.dispatch_ticket(ticket(plate)).await;
+    }
+
+    let leaving = client(1000);
+    let (tx, mut rx) = mpsc::channel(1);
+    let pending = shared_db
+        .add_ticket_dispatcher(ROAD, leaving, tx.clone())
+        .await;
+    assert_eq!(pending.len(), 3);
+    let flush = tokio::spawn({
+        let shared_db = shared_db.clone();
+        async move { shared_db.deliver_pending_tickets(pending, &tx).await }
+    });
+
+    // the writer takes the first ticket, then fails and hands back what it had queued
+    assert_eq!(rx.recv().await, Some(ticket("A1")));
+    rx.close();
+    while let Ok(queued) = rx.try_recv() {
+        shared_db.dispatch_ticket(queued).await;
+    }
+
+    assert!(flush.await.unwrap().is_err());
+    assert!(!shared_db.ticket_dispatcher_already_exists(&leaving).await);
+    let mut pending = shared_db.pending_tickets().await;
+    pending.sort_by_key(|ticket| format!("{ticket:?}"));
+    assert_eq!(pending, vec![ticket("B2"), ticket("C3")]);
+
+    // they go to the next dispatcher instead
+    let (tx, _rx) = mpsc::channel(16);
+    let flushed = shared_db
+        .add_ticket_dispatcher(ROAD, client(1001), tx)
+        .await;
+    assert_eq!(flushed.len(), 2);
+}
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+use speed_daemon::{
+    message::{InboundMessageType, OutboundMessageType},
+    policy::{RetentionPolicy, TicketPolicy},
+    state::Db,
+    types::{Day, Mile, PlateRoadStruct, Road, Timestamp, TimestampCameraStruct},
+};
+
+const DAY: Timestamp = 86400;
+
+fn observations(timestamps: &[Timestamp]) -> Vec<TimestampCameraStruct> {
+    timestamps
+        .iter()
+        .map(|timestamp| TimestampCameraStruct {
+            timestamp: *timestamp,
+            camera: InboundMessageType::IAmCamera {
+                road: 1,
+                mile: 0,
+                limit: 60,
+            },
+        })
+        .collect()
+}
+
+fn timestamps(observations: &[TimestampCameraStruct]) -> Vec<Timestamp> {
+    observations
+        .iter()
+        .map(|observation| observation.timestamp)
+        .collect()
+}
+
+#[test]
+fn retention_policy_cases() {
+    let max_age = RetentionPolicy {
+        max_age: Some(100),
+        ..RetentionPolicy::default()
+    };
+    let max_observations = RetentionPolicy {
+        max_observations: Some(2),
+        ..RetentionPolicy::default()
+    };
+    let ticketed_days = RetentionPolicy {
+        evict_ticketed_days: true,
+        ..RetentionPolicy::default()
+    };
+
+    // (name, policy, observations, newest, ticketed days, evicted)
+    type RetentionCase = (
+        &'static str,
+        RetentionPolicy,
+        &'static [Timestamp],
+        Timestamp,
+        &'static [Day],
+        &'static [Timestamp],
+    );
+    let cases: &[RetentionCase] = &[
+        (
+            "default keeps everything",
+            RetentionPolicy::default(),
+            &[0, 50, DAY],
+            10 * DAY,
+            &[0],
+            &[],
+        ),
+        (
+            "older than max_age",
+            max_age,
+            &[0, 99, 100, 150],
+            200,
+            &[],
+            &[0, 99],
+        ),
+        (
+            "newest younger than max_age",
+            max_age,
+            &[0, 50],
+            80,
+            &[],
+            &[],
+        ),
+        (
+            "oldest beyond max_observations",
+            max_observations,
+            &[10, 20, 30, 40],
+            40,
+            &[],
+            &[10, 20],
+        ),
+        (
+            "on ticketed days",
+            ticketed_days,
+            &[0, DAY, DAY + 1, 2 * DAY],
+            2 * DAY,
+            &[1],
+            &[DAY, DAY + 1],
+        ),
+        ("no ticketed days", ticketed_days, &[0, DAY], DAY, &[], &[]),
+    ];
+
+    for (name, policy, before, newest, days, expected) in cases {
+        let mut kept = observations(before);
+        let days: HashSet<Day> = days.iter().copied().collect();
+
+        let evicted = policy.evict(&mut kept, *newest, Some(&days));
+
+        assert_eq!(evicted, *expected, "{name}");
+        let mut remaining = before.to_vec();
+        remaining.retain(|timestamp| !expected.contains(timestamp));
+        assert_eq!(timestamps(&kept), remaining, "{name}");
+    }
+}
+
+#[test]
+fn ticketed_days_are_evicted_with_max_age() {
+    let policy = RetentionPolicy {
+        max_age: Some(DAY),
+        ..RetentionPolicy::default()
+    };
+
+    // the oldest observation kept is from day 2, which may still pair with a newer one
+    let newest = 3 * DAY + 100;
+    assert_eq!(policy.oldest_kept_day(newest), Some(2));
+
+    let mut days: HashSet<Day> = [0, 1, 2, 3].into_iter().collect();
+    assert_eq!(policy.evict_days(&mut days, newest), 2);
+    assert_eq!(days, [2, 3].into_iter().collect());
+
+    let mut days: HashSet<Day> = [0, 1].into_iter().collect();
+    assert_eq!(RetentionPolicy::default().oldest_kept_day(newest), None);
+    assert_eq!(RetentionPolicy::default().evict_days(&mut days, newest), 0);
+    assert_eq!(days.len(), 2);
+}
+
+#[tokio::test]
+async fn only_ticketed_cars_have_ticketed_days() {
+    let shared_db = Db::new();
+    let policy = TicketPolicy::default();
+
+    // slow, then fast on another road
+    for (road, plate, second) in [(1, "SLOW", 3600), (2, "FAST", 30)] {
+        let plate_road = PlateRoadStruct::new(plate.to_string(), road);
+        for (timestamp, mile) in [(0, 0), (second, 1)] {
+            let observation = TimestampCameraStruct {
+                timestamp,
+                camera: InboundMessageType::IAmCamera {
+                    road,
+                    mile,
+                    limit: 60,
+                },
+            };
+            shared_db
+                .add_plate_road_timestamp_camera(plate_road.clone(), observation.clone())
+                .await
+                .unwrap();
+            shared_db
+                .get_ticket_for_plate(&plate_road, &observation, &policy)
+                .await;
+        }
+    }
+
+    let snapshot = shared_db.snapshot().await;
+    let ticketed: Vec<_> = snapshot
+        .issued_ticket_days
+        .iter()
+        .map(|days| (days.plate.as_str(), days.days.clone()))
+        .collect();
+    assert_eq!(ticketed, vec![("FAST", vec![0])]);
+
+    // once the ticketed day is far enough behind, it's forgotten
+    let retention = RetentionPolicy {
+        max_age: Some(DAY),
+        ..RetentionPolicy::default()
+    };
+    for road in [1, 2] {
+        observe(&shared_db, "LATER", road, 3 * DAY).await;
+    }
+    assert_eq!(shared_db.evict_observations(&retention).await, 4);
+    assert!(shared_db.snapshot().await.issued_ticket_days.is_empty());
+}
+
+/// Records a sighting on `road`, checks it for tickets and returns them.
+async fn observe(
+    shared_db: &Db,
+    plate: &str,
+    road: Road,
+    timestamp: Timestamp,
+) -> Vec<OutboundMessageType> {
+    let plate_road = PlateRoadStruct::new(plate.to_string(), road);
+    let observation = TimestampCameraStruct {
+        timestamp,
+        camera: InboundMessageType::IAmCamera {
+            road,
+            mile: (timestamp / 30) as Mile,
+            limit: 60,
+        },
+    };
+
+    shared_db
+        .add_plate_road_timestamp_camera(plate_road.clone(), observation.clone())
+        .await
+        .unwrap();
+    shared_db
+        .get_ticket_for_plate(&plate_road, &observation, &TicketPolicy::default())
+        .await
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn a_clock_far_ahead_only_ages_its_own_road() {
+    let shared_db = Db::new();
+    let retention = RetentionPolicy {
+        max_age: Some(DAY),
+        ..RetentionPolicy::default()
+    };
+
+    // 120 mph on road 1
+    observe(&shared_db, "UN1X", 1, 0).await;
+    assert_eq!(observe(&shared_db, "UN1X", 1, 30).await.len(), 1);
+
+    // a camera on road 2 thinks it's the end of time
+    observe(&shared_db, "RE05BKG", 2, u32::MAX).await;
+    assert_eq!(shared_db.evict_observations(&retention).await, 0);
+
+    let snapshot = shared_db.snapshot().await;
+    assert_eq!(snapshot.observations[&1].observations, 2);
+    assert_eq!(snapshot.issued_ticket_days.len(), 1);
+
+    // so a late sighting the same day isn't ticketed again
+    assert!(observe(&shared_db, "UN1X", 1, 15).await.is_empty());
+    assert!(observe(&shared_db, "UN1X", 1, 60).await.is_empty());
+}
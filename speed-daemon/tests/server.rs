@@ -0,0 +1,318 @@
+use std::{net::SocketAddr, time::Duration};
+
+use futures::StreamExt;
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use tokio_util::codec::FramedRead;
+
+use speed_daemon::{
+    client::Client,
+    codec::ClientCodec,
+    message::{InboundMessageType, OutboundMessageType},
+};
+
+mod common;
+
+use common::{start_server, TestServer};
+
+const DAY: u32 = 86400;
+
+/// Long enough for anything the server is supposed to send.
+const PATIENCE: Duration = Duration::from_secs(5);
+
+/// Long enough to be fairly sure the server isn't going to send anything.
+const SILENCE: Duration = Duration::from_millis(300);
+
+async fn recv(client: &mut Client) -> OutboundMessageType {
+    timeout(PATIENCE, client.recv())
+        .await
+        .expect("no message from the server")
+        .expect("the server closed the connection")
+        .unwrap()
+}
+
+/// Expects an Error followed by the server closing the connection.
+async fn expect_error(client: &mut Client) {
+    assert!(matches!(recv(client).await, OutboundMessageType::Error(_)));
+
+    let closed = timeout(PATIENCE, client.recv())
+        .await
+        .expect("the connection is still open");
+    assert!(closed.is_none(), "got {closed:?} after the error");
+}
+
+/// Sends raw bytes, returning a reader for whatever the server answers.
+async fn send_raw(addr: SocketAddr, bytes: &[u8]) -> FramedRead<TcpStream, ClientCodec> {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(bytes).await.unwrap();
+
+    FramedRead::new(stream, ClientCodec::new())
+}
+
+fn ticket(
+    plate: &str,
+    road: u16,
+    (mile1, timestamp1): (u16, u32),
+    (mile2, timestamp2): (u16, u32),
+    speed: u16,
+) -> OutboundMessageType {
+    OutboundMessageType::Ticket {
+        plate: plate.to_string(),
+        road,
+        mile1,
+        timestamp1,
+        mile2,
+        timestamp2,
+        speed,
+    }
+}
+
+#[tokio::test]
+async fn basic_ticket() {
+    let addr = start_server().await;
+
+    let mut dispatcher = Client::dispatcher(addr, vec![123]).await.unwrap();
+
+    let mut camera1 = Client::camera(addr, 123, 8, 60).await.unwrap();
+    camera1.plate("UN1X".into(), 0).await.unwrap();
+    let mut camera2 = Client::camera(addr, 123, 9, 60).await.unwrap();
+    camera2.plate("UN1X".into(), 45).await.unwrap();
+
+    assert_eq!(
+        recv(&mut dispatcher).await,
+        ticket("UN1X", 123, (8, 0), (9, 45), 8000)
+    );
+}
+
+#[tokio::test]
+async fn ticket_before_dispatcher_exists() {
+    let addr = start_server().await;
+
+    let mut camera1 = Client::camera(addr, 7, 100, 40).await.unwrap();
+    camera1.plate("RE05BKG".into(), 1000).await.unwrap();
+    let mut camera2 = Client::camera(addr, 7, 110, 40).await.unwrap();
+    camera2.plate("RE05BKG".into(), 1600).await.unwrap();
+
+    // give the engine time to park the ticket
+    tokio::time::sleep(SILENCE).await;
+
+    let mut dispatcher = Client::dispatcher(addr, vec![7]).await.unwrap();
+    assert_eq!(
+        recv(&mut dispatcher).await,
+        ticket("RE05BKG", 7, (100, 1000), (110, 1600), 6000)
+    );
+}
+
+#[tokio::test]
+async fn at_most_one_ticket_per_day() {
+    let addr = start_server().await;
+
+    let mut dispatcher = Client::dispatcher(addr, vec![1]).await.unwrap();
+    let mut camera1 = Client::camera(addr, 1, 0, 60).await.unwrap();
+    let mut camera2 = Client::camera(addr, 1, 100, 60).await.unwrap();
+
+    // 100 mph twice on day 0, then again on day 1
+    camera1.plate("CAR1".into(), 0).await.unwrap();
+    camera2.plate("CAR1".into(), 3600).await.unwrap();
+    camera1.plate("CAR1".into(), 7200).await.unwrap();
+    camera1.plate("CAR1".into(), DAY).await.unwrap();
+    camera2.plate("CAR1".into(), DAY + 3600).await.unwrap();
+
+    let mut tickets = vec![recv(&mut dispatcher).await, recv(&mut dispatcher).await];
+    tickets.sort_by_key(|ticket| match ticket {
+        OutboundMessageType::Ticket { timestamp1, .. } => *timestamp1,
+        _ => 0,
+    });
+    assert_eq!(
+        tickets,
+        vec![
+            ticket("CAR1", 1, (0, 0), (100, 3600), 10000),
+            ticket("CAR1", 1, (0, DAY), (100, DAY + 3600), 10000),
+        ]
+    );
+
+    assert!(
+        timeout(SILENCE, dispatcher.recv()).await.is_err(),
+        "a third ticket was issued"
+    );
+}
+
+#[tokio::test]
+async fn duplicate_camera_is_an_error() {
+    let addr = start_server().await;
+
+    let mut camera = Client::camera(addr, 1, 0, 60).await.unwrap();
+    camera
+        .send(InboundMessageType::IAmCamera {
+            road: 1,
+            mile: 0,
+            limit: 60,
+        })
+        .await
+        .unwrap();
+
+    expect_error(&mut camera).await;
+}
+
+#[tokio::test]
+async fn duplicate_plate_is_an_error() {
+    let addr = start_server().await;
+
+    let mut camera = Client::camera(addr, 1, 0, 60).await.unwrap();
+    camera.plate("UN1X".into(), 1000).await.unwrap();
+    camera.plate("UN1X".into(), 1000).await.unwrap();
+
+    expect_error(&mut camera).await;
+}
+
+#[tokio::test]
+async fn another_camera_at_the_same_time_is_ignored() {
+    let addr = start_server().await;
+
+    let mut dispatcher = Client::dispatcher(addr, vec![123]).await.unwrap();
+    let mut camera1 = Client::camera(addr, 123, 8, 60).await.unwrap();
+    camera1.plate("UN1X".into(), 0).await.unwrap();
+    let mut camera2 = Client::camera(addr, 123, 9, 60).await.unwrap();
+    camera2.plate("UN1X".into(), 0).await.unwrap();
+    camera2.plate("UN1X".into(), 45).await.unwrap();
+
+    assert_eq!(
+        recv(&mut dispatcher).await,
+        ticket("UN1X", 123, (8, 0), (9, 45), 8000)
+    );
+    assert!(
+        timeout(SILENCE, camera2.recv()).await.is_err(),
+        "the second camera got an answer"
+    );
+}
+
+#[tokio::test]
+async fn identifying_twice_is_an_error() {
+    let addr = start_server().await;
+
+    // a dispatcher that then claims to be a camera, and would go on to send plates
+    let mut dispatcher = Client::dispatcher(addr, vec![1]).await.unwrap();
+    dispatcher
+        .send(InboundMessageType::IAmCamera {
+            road: 1,
+            mile: 0,
+            limit: 60,
+        })
+        .await
+        .unwrap();
+    expect_error(&mut dispatcher).await;
+
+    // a dispatcher for no roads at all is still a dispatcher
+    let mut dispatcher = Client::dispatcher(addr, vec![]).await.unwrap();
+    dispatcher
+        .send(InboundMessageType::IAmDispatcher { roads: vec![] })
+        .await
+        .unwrap();
+    expect_error(&mut dispatcher).await;
+
+    let mut camera = Client::camera(addr, 1, 0, 60).await.unwrap();
+    camera
+        .send(InboundMessageType::IAmDispatcher { roads: vec![1] })
+        .await
+        .unwrap();
+    expect_error(&mut camera).await;
+}
+
+#[tokio::test]
+async fn heartbeats() {
+    let addr = start_server().await;
+
+    // every 0.1 seconds, before the client even said what it is
+    let mut client = Client::connect(addr).await.unwrap();
+    client.want_heartbeat(1).await.unwrap();
+    for _ in 0..3 {
+        assert_eq!(recv(&mut client).await, OutboundMessageType::Heartbeat);
+    }
+
+    // a client may only ask once
+    client.want_heartbeat(1).await.unwrap();
+    loop {
+        match recv(&mut client).await {
+            OutboundMessageType::Heartbeat => continue,
+            OutboundMessageType::Error(_) => break,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn no_heartbeats_when_the_interval_is_zero() {
+    let addr = start_server().await;
+
+    let mut client = Client::camera(addr, 1, 0, 60).await.unwrap();
+    client.want_heartbeat(0).await.unwrap();
+
+    assert!(timeout(SILENCE, client.recv()).await.is_err());
+}
+
+#[tokio::test]
+async fn plate_from_a_client_that_is_not_a_camera() {
+    let addr = start_server().await;
+
+    let mut client = Client::connect(addr).await.unwrap();
+    client.plate("UN1X".into(), 0).await.unwrap();
+    expect_error(&mut client).await;
+
+    let mut dispatcher = Client::dispatcher(addr, vec![1]).await.unwrap();
+    dispatcher.plate("UN1X".into(), 0).await.unwrap();
+    expect_error(&mut dispatcher).await;
+}
+
+#[tokio::test]
+async fn illegal_messages_are_errors() {
+    let addr = start_server().await;
+
+    // unknown message type, then a Ticket, which only the server may send
+    for bytes in [&[0x99][..], &[0x21, 0x00, 0x00, 0x01]] {
+        let mut reader = send_raw(addr, bytes).await;
+
+        let message = timeout(PATIENCE, reader.next()).await.unwrap();
+        assert!(
+            matches!(message, Some(Ok(OutboundMessageType::Error(_)))),
+            "{bytes:?}: got {message:?}"
+        );
+
+        let closed = timeout(PATIENCE, reader.next()).await.unwrap();
+        assert!(closed.is_none(), "{bytes:?}: still open");
+    }
+}
+
+#[tokio::test]
+async fn shutdown_delivers_tickets_then_disconnects() {
+    let TestServer {
+        addr,
+        ticket_engine,
+        shutdown,
+        ..
+    } = TestServer::start().await;
+
+    let mut dispatcher = Client::dispatcher(addr, vec![123]).await.unwrap();
+    let mut camera1 = Client::camera(addr, 123, 8, 60).await.unwrap();
+    camera1.plate("UN1X".into(), 0).await.unwrap();
+    let mut camera2 = Client::camera(addr, 123, 9, 60).await.unwrap();
+    camera2.plate("UN1X".into(), 45).await.unwrap();
+
+    // let the server read the plates, the engine may still be busy with them
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    timeout(PATIENCE, shutdown.run(&ticket_engine, PATIENCE))
+        .await
+        .expect("the shutdown took longer than its deadline");
+
+    assert_eq!(
+        recv(&mut dispatcher).await,
+        ticket("UN1X", 123, (8, 0), (9, 45), 8000)
+    );
+    for client in [&mut dispatcher, &mut camera1, &mut camera2] {
+        let closed = timeout(PATIENCE, client.recv()).await.unwrap();
+        assert!(closed.is_none(), "got {closed:?} after the shutdown");
+    }
+
+    assert!(
+        Client::connect(addr).await.is_err(),
+        "new clients are still accepted"
+    );
+}
@@ -0,0 +1,227 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use tokio::sync::mpsc;
+
+use speed_daemon::{
+    message::{InboundMessageType, OutboundMessageType},
+    policy::RetentionPolicy,
+    state::Db,
+    types::{Mile, PlateRoadStruct, Timestamp, TimestampCameraStruct},
+};
+
+const ROAD: u16 = 1;
+const SHARDS: usize = 4;
+
+/// A database file of its own for every test, removed when the test is done.
+struct TempDb(PathBuf);
+
+impl TempDb {
+    fn new(name: &str) -> TempDb {
+        let path =
+            std::env::temp_dir().join(format!("speed-daemon-{}-{name}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        TempDb(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn dispatcher_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 1000))
+}
+
+/// Records a sighting the way a camera connection and the ticket engine would,
+/// returns the tickets it led to.
+async fn observe(
+    shared_db: &Db,
+    plate: &str,
+    timestamp: Timestamp,
+    mile: Mile,
+) -> Vec<OutboundMessageType> {
+    let plate_road = PlateRoadStruct::new(plate.to_string(), ROAD);
+    let observation = TimestampCameraStruct {
+        timestamp,
+        camera: InboundMessageType::IAmCamera {
+            road: ROAD,
+            mile,
+            limit: 60,
+        },
+    };
+
+    shared_db
+        .add_plate_road_timestamp_camera(plate_road.clone(), observation.clone())
+        .await
+        .unwrap();
+    let tickets = shared_db
+        .get_ticket_for_plate(&plate_road, &observation, &shared_db.ticket_policy(ROAD))
+        .await
+        .unwrap_or_default();
+    for ticket in tickets.iter() {
+        shared_db.issue_ticket(ticket.clone()).await;
+    }
+
+    tickets
+}
+
+/// Connects a dispatcher for the road and returns what it's handed right away.
+async fn flush(shared_db: &Db) -> Vec<OutboundMessageType> {
+    let (tx, _rx) = mpsc::channel(16);
+    shared_db
+        .add_ticket_dispatcher(ROAD, dispatcher_addr(), tx)
+        .await
+}
+
+fn plates(tickets: &[OutboundMessageType]) -> Vec<&str> {
+    let mut plates: Vec<_> = tickets
+        .iter()
+        .filter_map(|ticket| match ticket {
+            OutboundMessageType::Ticket { plate, .. } => Some(plate.as_str()),
+            _ => None,
+        })
+        .collect();
+    plates.sort_unstable();
+    plates
+}
+
+#[tokio::test]
+async fn a_restart_neither_reissues_nor_redelivers_tickets() {
+    let db = TempDb::new("restart");
+
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 100).await.unwrap();
+        assert!(
+            db.path().with_extension("db-wal").exists(),
+            "not in write-ahead log mode"
+        );
+        assert!(observe(&shared_db, "UN1X", 0, 0).await.is_empty());
+        assert_eq!(observe(&shared_db, "UN1X", 30, 1).await.len(), 1);
+    }
+
+    // everything comes back: the observations, the ticketed day and the undelivered ticket
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 100).await.unwrap();
+        let snapshot = shared_db.snapshot().await;
+        assert_eq!(snapshot.observations[&ROAD].observations, 2);
+        assert_eq!(snapshot.issued_ticket_days.len(), 1);
+        assert_eq!(snapshot.issued_ticket_days[0].days, vec![0]);
+        assert_eq!(plates(&shared_db.pending_tickets().await), vec!["UN1X"]);
+
+        // speeding again the same day isn't ticketed again
+        assert!(observe(&shared_db, "UN1X", 60, 2).await.is_empty());
+
+        assert_eq!(plates(&flush(&shared_db).await), vec!["UN1X"]);
+    }
+
+    // the ticket has been handed over, it isn't delivered a second time
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 100).await.unwrap();
+        assert!(shared_db.pending_tickets().await.is_empty());
+        assert!(flush(&shared_db).await.is_empty());
+        assert_eq!(
+            shared_db.snapshot().await.observations[&ROAD].observations,
+            3
+        );
+    }
+}
+
+#[tokio::test]
+async fn spilled_tickets_are_delivered_once() {
+    let db = TempDb::new("spill");
+    let speeders = ["A1", "B2", "C3"];
+
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 1).await.unwrap();
+        for plate in speeders {
+            observe(&shared_db, plate, 0, 0).await;
+            assert_eq!(observe(&shared_db, plate, 30, 1).await.len(), 1);
+        }
+
+        let snapshot = shared_db.snapshot().await;
+        assert_eq!(snapshot.pending_tickets.len(), 1);
+        assert_eq!(snapshot.spilled_tickets[&ROAD], 2);
+    }
+
+    // a restart keeps no more than the limit in memory either
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 1).await.unwrap();
+        let snapshot = shared_db.snapshot().await;
+        assert_eq!(snapshot.pending_tickets.len(), 1);
+        assert_eq!(snapshot.spilled_tickets[&ROAD], 2);
+
+        assert_eq!(plates(&flush(&shared_db).await), speeders);
+        assert!(shared_db.snapshot().await.spilled_tickets.is_empty());
+    }
+
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 1).await.unwrap();
+        assert!(flush(&shared_db).await.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn evicted_state_stays_evicted_after_a_restart() {
+    let db = TempDb::new("evict");
+    let retention = RetentionPolicy {
+        max_age: Some(86400),
+        ..RetentionPolicy::default()
+    };
+
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 100).await.unwrap();
+        observe(&shared_db, "UN1X", 0, 0).await;
+        assert_eq!(observe(&shared_db, "UN1X", 30, 1).await.len(), 1);
+        observe(&shared_db, "RE05BKG", 3 * 86400, 0).await;
+
+        assert_eq!(shared_db.evict_observations(&retention).await, 2);
+    }
+
+    let shared_db = Db::with_sqlite(db.path(), SHARDS, 100).await.unwrap();
+    let snapshot = shared_db.snapshot().await;
+    assert_eq!(snapshot.observations[&ROAD].observations, 1);
+    assert!(snapshot.issued_ticket_days.is_empty());
+    // the ticket itself is still owed to a dispatcher
+    assert_eq!(plates(&shared_db.pending_tickets().await), vec!["UN1X"]);
+}
+
+#[tokio::test]
+async fn concurrent_observations_are_all_persisted() {
+    let db = TempDb::new("concurrent");
+
+    {
+        let shared_db = Db::with_sqlite(db.path(), SHARDS, 100).await.unwrap();
+        let cameras: Vec<_> = (0..500)
+            .map(|n: u32| {
+                let shared_db = shared_db.clone();
+                tokio::spawn(async move {
+                    let plate = format!("P{}", n % 50);
+                    observe(&shared_db, &plate, n, (n % 7) as Mile).await
+                })
+            })
+            .collect();
+        for camera in cameras {
+            camera.await.unwrap();
+        }
+    }
+
+    let shared_db = Db::with_sqlite(db.path(), SHARDS, 100).await.unwrap();
+    assert_eq!(
+        shared_db.snapshot().await.observations[&ROAD].observations,
+        500
+    );
+}
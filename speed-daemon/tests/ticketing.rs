@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+
+use speed_daemon::{
+    message::{InboundMessageType, OutboundMessageType},
+    policy::{Rounding, TicketPolicy},
+    ticket::{check_new_observation, AverageSpeed},
+    types::{Day, Limit, Mile, PlateRoadStruct, Speed, Timestamp, TimestampCameraStruct},
+};
+
+const ROAD: u16 = 123;
+const DAY: Timestamp = 86400;
+
+struct Case {
+    name: &'static str,
+    limit: Limit,
+    /// Days that were already ticketed before the first observation arrives.
+    ticketed_days: &'static [Day],
+    /// (timestamp, mile) in the order the observations arrive.
+    observations: &'static [(Timestamp, Mile)],
+    /// (mile1, timestamp1, mile2, timestamp2, speed) of every ticket, in issue order.
+    tickets: &'static [(Mile, Timestamp, Mile, Timestamp, Speed)],
+    /// Ticketed days once every observation was processed.
+    days_after: &'static [Day],
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "spec example",
+        limit: 60,
+        ticketed_days: &[],
+        observations: &[(0, 8), (45, 9)],
+        tickets: &[(8, 0, 9, 45, 8000)],
+        days_after: &[0],
+    },
+    Case {
+        name: "exactly at the limit",
+        limit: 60,
+        ticketed_days: &[],
+        observations: &[(0, 0), (3600, 60)],
+        tickets: &[],
+        days_after: &[],
+    },
+    Case {
+        name: "one mph over the limit",
+        limit: 60,
+        ticketed_days: &[],
+        observations: &[(0, 0), (3600, 61)],
+        tickets: &[(0, 0, 61, 3600, 6100)],
+        days_after: &[0],
+    },
+    Case {
+        name: "observations arrive out of order",
+        limit: 60,
+        ticketed_days: &[],
+        observations: &[(45, 9), (0, 8)],
+        tickets: &[(8, 0, 9, 45, 8000)],
+        days_after: &[0],
+    },
+    Case {
+        name: "driving towards lower mile markers",
+        limit: 60,
+        ticketed_days: &[],
+        observations: &[(0, 9), (45, 8)],
+        tickets: &[(9, 0, 8, 45, 8000)],
+        days_after: &[0],
+    },
+    Case {
+        name: "only chronological neighbours are compared",
+        limit: 100,
+        ticketed_days: &[],
+        // 0 -> 3600 is 50 mph, 3600 -> 7200 is 150 mph, 0 -> 7200 would be 100 mph
+        observations: &[(0, 0), (3600, 50), (7200, 200)],
+        tickets: &[(50, 3600, 200, 7200, 15000)],
+        days_after: &[0],
+    },
+    Case {
+        name: "observation inserted between two existing ones",
+        limit: 60,
+        ticketed_days: &[],
+        // 0 -> 7200 is 60 mph, once 3600 arrives 0 -> 3600 becomes 100 mph
+        observations: &[(0, 0), (7200, 120), (3600, 100)],
+        tickets: &[(0, 0, 100, 3600, 10000)],
+        days_after: &[0],
+    },
+    Case {
+        name: "at most one ticket per day",
+        limit: 60,
+        ticketed_days: &[],
+        observations: &[(0, 0), (3600, 100), (7200, 200)],
+        tickets: &[(0, 0, 100, 3600, 10000)],
+        days_after: &[0],
+    },
+    Case {
+        name: "speeding on two different days",
+        limit: 60,
+        ticketed_days: &[],
+        observations: &[(0, 0), (3600, 100), (DAY, 200), (DAY + 3600, 300)],
+        tickets: &[(0, 0, 100, 3600, 10000), (200, DAY, 300, DAY + 3600, 10000)],
+        days_after: &[0, 1],
+    },
+    Case {
+        name: "a ticket spanning several days marks every day in between",
+        limit: 60,
+        ticketed_days: &[],
+        // 10000 miles in just over two days, then two more speeding legs on day 1 and day 2
+        observations: &[
+            (0, 0),
+            (2 * DAY + 100, 10000),
+            (DAY + 100, 5000),
+            (2 * DAY + 3700, 10500),
+        ],
+        tickets: &[(0, 0, 10000, 2 * DAY + 100, 20800)],
+        days_after: &[0, 1, 2],
+    },
+    Case {
+        name: "a day ticketed before blocks a span that contains it",
+        limit: 60,
+        ticketed_days: &[1],
+        observations: &[(0, 0), (2 * DAY + 100, 10000)],
+        tickets: &[],
+        days_after: &[1],
+    },
+    Case {
+        name: "a day ticketed before doesn't block other days",
+        limit: 60,
+        ticketed_days: &[0],
+        observations: &[(0, 0), (3600, 100), (DAY, 200), (DAY + 3600, 300)],
+        tickets: &[(200, DAY, 300, DAY + 3600, 10000)],
+        days_after: &[0, 1],
+    },
+];
+
+fn observation(timestamp: Timestamp, mile: Mile, limit: Limit) -> TimestampCameraStruct {
+    TimestampCameraStruct {
+        timestamp,
+        camera: InboundMessageType::IAmCamera {
+            road: ROAD,
+            mile,
+            limit,
+        },
+    }
+}
+
+fn ticket_fields(ticket: &OutboundMessageType) -> (Mile, Timestamp, Mile, Timestamp, Speed) {
+    match ticket {
+        OutboundMessageType::Ticket {
+            mile1,
+            timestamp1,
+            mile2,
+            timestamp2,
+            speed,
+            ..
+        } => (*mile1, *timestamp1, *mile2, *timestamp2, *speed),
+        other => panic!("expected a ticket, got {other:?}"),
+    }
+}
+
+#[test]
+fn ticketing_edge_cases() {
+    let plate_road = PlateRoadStruct::new(String::from("UN1X"), ROAD);
+
+    for case in CASES {
+        let mut observations: Vec<TimestampCameraStruct> = Vec::new();
+        let mut issued_days: HashSet<Day> = case.ticketed_days.iter().copied().collect();
+        let mut tickets = Vec::new();
+
+        for &(timestamp, mile) in case.observations {
+            let new_observation = observation(timestamp, mile, case.limit);
+
+            // same bookkeeping as the storage backends: keep the observations sorted
+            if let Err(position) = observations.binary_search(&new_observation) {
+                observations.insert(position, new_observation.clone());
+            }
+
+            tickets.extend(check_new_observation(
+                &plate_road,
+                &observations,
+                &new_observation,
+                &TicketPolicy::default(),
+                &mut issued_days,
+            ));
+        }
+
+        let tickets: Vec<_> = tickets.iter().map(ticket_fields).collect();
+        assert_eq!(tickets, case.tickets, "{}: tickets", case.name);
+
+        let expected_days: HashSet<Day> = case.days_after.iter().copied().collect();
+        assert_eq!(issued_days, expected_days, "{}: ticketed days", case.name);
+    }
+}
+
+#[test]
+fn ticket_policy_cases() {
+    let spec = TicketPolicy::default();
+    let tolerant = TicketPolicy {
+        tolerance: 100,
+        ..TicketPolicy::default()
+    };
+    let half_mph = TicketPolicy {
+        tolerance: 50,
+        ..TicketPolicy::default()
+    };
+    let rounding_down = TicketPolicy {
+        rounding: Rounding::Down,
+        ..TicketPolicy::default()
+    };
+    let exact = TicketPolicy {
+        exact_speed: true,
+        ..TicketPolicy::default()
+    };
+
+    // (name, policy, miles, seconds, limit, expected ticket speed)
+    type PolicyCase = (&'static str, TicketPolicy, u32, u32, Limit, Option<Speed>);
+    let cases: &[PolicyCase] = &[
+        ("at the limit", spec, 6000, 360000, 60, None),
+        ("rounds down to the limit", spec, 6049, 360000, 60, None),
+        (
+            "rounds up over the limit",
+            spec,
+            6050,
+            360000,
+            60,
+            Some(6100),
+        ),
+        ("within the tolerance", tolerant, 6100, 360000, 60, None),
+        (
+            "beyond the tolerance",
+            tolerant,
+            6150,
+            360000,
+            60,
+            Some(6200),
+        ),
+        (
+            "rounds over the limit but within half a mph",
+            half_mph,
+            6050,
+            360000,
+            60,
+            None,
+        ),
+        (
+            "just beyond half a mph",
+            half_mph,
+            6051,
+            360000,
+            60,
+            Some(6100),
+        ),
+        (
+            "rounding down keeps it legal",
+            rounding_down,
+            6099,
+            360000,
+            60,
+            None,
+        ),
+        (
+            "rounding down over the limit",
+            rounding_down,
+            6100,
+            360000,
+            60,
+            Some(6100),
+        ),
+        (
+            "exact speed is reported",
+            exact,
+            61234,
+            3600000,
+            60,
+            Some(6123),
+        ),
+        ("speed is capped", spec, 800, 3600, 60, Some(Speed::MAX)),
+    ];
+
+    for (name, policy, miles, seconds, limit, expected) in cases {
+        let average_speed = AverageSpeed {
+            miles: *miles,
+            seconds: *seconds,
+        };
+        assert_eq!(
+            policy.ticket_speed(average_speed, *limit),
+            *expected,
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn ticket_policy_from_str() {
+    let policy: TicketPolicy = "tolerance=0.5, rounding=up, exact=true".parse().unwrap();
+    assert_eq!(
+        policy,
+        TicketPolicy {
+            tolerance: 50,
+            rounding: Rounding::Up,
+            exact_speed: true,
+        }
+    );
+
+    assert!("rounding=sideways".parse::<TicketPolicy>().is_err());
+    assert!("tolerance=-1".parse::<TicketPolicy>().is_err());
+    assert!("tolerance=0.125".parse::<TicketPolicy>().is_err());
+    assert!("speed".parse::<TicketPolicy>().is_err());
+}
@@ -0,0 +1,61 @@
+use speed_daemon::{
+    errors::SpeedDaemonError,
+    message::InboundMessageType,
+    validation::{validate, validate_limit, validate_plate},
+};
+
+#[test]
+fn plates() {
+    for plate in ["UN1X", "RE05BKG", "A", "007"] {
+        assert!(validate_plate(plate).is_ok(), "{plate:?}");
+    }
+
+    for plate in ["", "un1x", "UN 1X", "UN-1X", "ÜN1X", "UN1X\n"] {
+        assert!(
+            matches!(validate_plate(plate), Err(SpeedDaemonError::InvalidPlate(p)) if p == plate),
+            "{plate:?}"
+        );
+    }
+}
+
+#[test]
+fn limits() {
+    assert!(matches!(
+        validate_limit(0),
+        Err(SpeedDaemonError::InvalidLimit)
+    ));
+    assert!(validate_limit(1).is_ok());
+    assert!(validate_limit(u16::MAX).is_ok());
+}
+
+#[test]
+fn messages() {
+    let valid = [
+        InboundMessageType::Plate {
+            plate: "UN1X".into(),
+            timestamp: 0,
+        },
+        InboundMessageType::IAmCamera {
+            road: 1,
+            mile: 0,
+            limit: 60,
+        },
+        InboundMessageType::WantHeartbeat { interval: 0 },
+        InboundMessageType::IAmDispatcher { roads: vec![] },
+    ];
+    for message in valid {
+        assert_eq!(validate(message.clone()).unwrap(), message);
+    }
+
+    assert!(validate(InboundMessageType::Plate {
+        plate: "un1x".into(),
+        timestamp: 0,
+    })
+    .is_err());
+    assert!(validate(InboundMessageType::IAmCamera {
+        road: 1,
+        mile: 0,
+        limit: 0,
+    })
+    .is_err());
+}